@@ -1,7 +1,8 @@
-use std::io::{BufReader, Cursor};
+use std::collections::HashMap;
+use std::io::{sink, BufReader, Cursor};
 
-use criterion::{criterion_group, criterion_main, Criterion};
-use envsubst::Parser;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use envsubst::{MissingVariablePolicy, Parser};
 
 const TEMPLATE: &str = r#"${PATH}
 ${PWD}
@@ -10,6 +11,26 @@ $PATH
 $PWD
 "#;
 
+const LITERAL: &str = "The quick brown fox jumps over the lazy dog, again and again.\n";
+
+fn variables() -> HashMap<String, String> {
+    (0..100)
+        .map(|index| (format!("VARIABLE_{}", index), format!("value {}", index)))
+        .collect()
+}
+
+fn render(template: &str, variables: &HashMap<String, String>) {
+    let input = BufReader::new(Cursor::new(template));
+    let mut parser = Parser::with_source(
+        input,
+        sink(),
+        MissingVariablePolicy::Empty,
+        None,
+        |name: &str| variables.get(name).cloned(),
+    );
+    parser.process().unwrap();
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let huge_template = (0..10_000)
         .map(|_| TEMPLATE.to_owned())
@@ -19,11 +40,33 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| {
             let input = BufReader::new(Cursor::new(&huge_template));
             let output = Cursor::new(vec![]);
-            let mut s = Parser::new(input, output, true);
+            let mut s = Parser::new(input, output, MissingVariablePolicy::Empty, None);
             s.process().unwrap();
         })
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+fn throughput_benchmark(c: &mut Criterion) {
+    let variables = variables();
+    let literal_heavy = LITERAL.repeat(100_000) + "$VARIABLE_1";
+    let single_line = LITERAL.trim_end().repeat(100_000) + "${VARIABLE_1}";
+    let variable_heavy = (0..200_000)
+        .map(|index| format!("${{VARIABLE_{}}} $VARIABLE_{} ", index % 100, index % 50))
+        .collect::<String>();
+    let long_word = format!("${{UNSET:-{}}}", LITERAL.repeat(30_000));
+
+    let mut group = c.benchmark_group("throughput");
+    for &(name, template) in &[
+        ("literal-heavy", &literal_heavy),
+        ("single-line", &single_line),
+        ("variable-heavy", &variable_heavy),
+        ("long-word", &long_word),
+    ] {
+        group.throughput(Throughput::Bytes(template.len() as u64));
+        group.bench_function(name, |b| b.iter(|| render(template, &variables)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark, throughput_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::source::{SourceError, VariableSource};
+
+/// The formats a `Document` can be read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Guesses the format of a file from its extension.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?;
+        extension.to_ascii_lowercase().parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!(
+                "invalid format '{}', expected one of json, yaml or toml",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "JSON"),
+            Format::Yaml => write!(f, "YAML"),
+            Format::Toml => write!(f, "TOML"),
+        }
+    }
+}
+
+/// Why a document could not be loaded.
+#[derive(Debug)]
+pub enum DocumentError {
+    Io(io::Error),
+    /// The format of a file could not be guessed from its extension.
+    UnknownFormat(PathBuf),
+    /// The crate was built without support for the format.
+    UnsupportedFormat(Format),
+    Parse {
+        format: Format,
+        source: SourceError,
+    },
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocumentError::Io(error) => error.fmt(f),
+            DocumentError::UnknownFormat(path) => write!(
+                f,
+                "Unknown format for {}, expected a .json, .yaml, .yml or .toml file",
+                path.display()
+            ),
+            DocumentError::UnsupportedFormat(format) => {
+                write!(f, "{} support is not enabled", format)
+            }
+            DocumentError::Parse { format, source } => {
+                write!(f, "Invalid {} document: {}", format, source)
+            }
+        }
+    }
+}
+
+impl StdError for DocumentError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            DocumentError::Io(error) => error.source(),
+            DocumentError::Parse { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DocumentError {
+    fn from(error: io::Error) -> Self {
+        DocumentError::Io(error)
+    }
+}
+
+/// A document of any format, once its scalars are turned into strings.
+#[cfg_attr(
+    not(any(feature = "serde_json", feature = "serde_yaml", feature = "toml")),
+    allow(dead_code)
+)]
+enum Tree {
+    /// `None` for a null, which is handled like a variable that is not set.
+    Scalar(Option<String>),
+    List(Vec<Tree>),
+    Map(Vec<(String, Tree)>),
+}
+
+/// Variables read from a JSON, YAML or TOML document.
+///
+/// The document is flattened, every scalar is a variable named after the keys
+/// leading to it joined with a separator. With `.` as separator,
+/// `{"database": {"hosts": ["a", "b"]}}` sets `database.hosts.0` to `a` and
+/// `database.hosts.1` to `b`. Names can also be uppercased, so with `_` as
+/// separator the same document sets `DATABASE_HOSTS_0`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Document {
+    variables: HashMap<String, String>,
+}
+
+impl Document {
+    pub fn parse(text: &str, format: Format, separator: &str) -> Result<Self, DocumentError> {
+        let tree = match format {
+            Format::Json => json(text)?,
+            Format::Yaml => yaml(text)?,
+            Format::Toml => toml(text)?,
+        };
+        let mut variables = HashMap::new();
+        flatten(tree, String::new(), separator, &mut variables);
+        Ok(Self { variables })
+    }
+
+    /// Reads a document in the format given by the extension of `path`.
+    pub fn from_path<P: AsRef<Path>>(path: P, separator: &str) -> Result<Self, DocumentError> {
+        let path = path.as_ref();
+        let format =
+            Format::from_path(path).ok_or_else(|| DocumentError::UnknownFormat(path.into()))?;
+        Self::parse(&fs::read_to_string(path)?, format, separator)
+    }
+
+    /// Uppercases the names of the variables.
+    pub fn uppercase(self) -> Self {
+        let variables = self
+            .variables
+            .into_iter()
+            .map(|(name, value)| (name.to_uppercase(), value))
+            .collect();
+        Self { variables }
+    }
+
+    /// Adds the variables of `other`, they override the ones that are already
+    /// set.
+    pub fn extend(&mut self, other: Document) {
+        self.variables.extend(other.variables);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(String::as_str)
+    }
+}
+
+impl VariableSource for Document {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        Ok(self.get(name).map(str::to_owned))
+    }
+}
+
+fn flatten(tree: Tree, name: String, separator: &str, variables: &mut HashMap<String, String>) {
+    let join = |key: &str| {
+        if name.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}{}{}", name, separator, key)
+        }
+    };
+    match tree {
+        Tree::Scalar(Some(value)) => {
+            variables.insert(name, value);
+        }
+        Tree::Scalar(None) => {}
+        Tree::List(items) => {
+            for (index, item) in items.into_iter().enumerate() {
+                flatten(item, join(&index.to_string()), separator, variables);
+            }
+        }
+        Tree::Map(entries) => {
+            for (key, value) in entries {
+                flatten(value, join(&key), separator, variables);
+            }
+        }
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "serde_json", feature = "serde_yaml", feature = "toml")),
+    allow(dead_code)
+)]
+fn parse_error<E>(format: Format) -> impl FnOnce(E) -> DocumentError
+where
+    E: StdError + Send + Sync + 'static,
+{
+    move |error| DocumentError::Parse {
+        format,
+        source: Box::new(error),
+    }
+}
+
+#[cfg(feature = "serde_json")]
+fn json(text: &str) -> Result<Tree, DocumentError> {
+    use serde_json::Value;
+
+    fn tree(value: Value) -> Tree {
+        match value {
+            Value::Null => Tree::Scalar(None),
+            Value::Bool(value) => Tree::Scalar(Some(value.to_string())),
+            Value::Number(value) => Tree::Scalar(Some(value.to_string())),
+            Value::String(value) => Tree::Scalar(Some(value)),
+            Value::Array(items) => Tree::List(items.into_iter().map(tree).collect()),
+            Value::Object(entries) => Tree::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, tree(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    let value = serde_json::from_str(text).map_err(parse_error(Format::Json))?;
+    Ok(tree(value))
+}
+
+#[cfg(not(feature = "serde_json"))]
+fn json(_: &str) -> Result<Tree, DocumentError> {
+    Err(DocumentError::UnsupportedFormat(Format::Json))
+}
+
+#[cfg(feature = "serde_yaml")]
+fn yaml(text: &str) -> Result<Tree, DocumentError> {
+    use serde_yaml::Value;
+
+    fn scalar(value: Value) -> Result<Option<String>, Value> {
+        match value {
+            Value::Null => Ok(None),
+            Value::Bool(value) => Ok(Some(value.to_string())),
+            Value::Number(value) => Ok(Some(value.to_string())),
+            Value::String(value) => Ok(Some(value)),
+            value => Err(value),
+        }
+    }
+
+    fn tree(value: Value) -> Tree {
+        match scalar(value) {
+            Ok(value) => Tree::Scalar(value),
+            Err(Value::Sequence(items)) => Tree::List(items.into_iter().map(tree).collect()),
+            Err(Value::Mapping(entries)) => Tree::Map(
+                entries
+                    .into_iter()
+                    // Keys that are not scalars can't be part of a name.
+                    .filter_map(|(key, value)| Some((scalar(key).ok()??, tree(value))))
+                    .collect(),
+            ),
+            Err(_) => unreachable!("every other value is a scalar"),
+        }
+    }
+
+    let value = serde_yaml::from_str(text).map_err(parse_error(Format::Yaml))?;
+    Ok(tree(value))
+}
+
+#[cfg(not(feature = "serde_yaml"))]
+fn yaml(_: &str) -> Result<Tree, DocumentError> {
+    Err(DocumentError::UnsupportedFormat(Format::Yaml))
+}
+
+#[cfg(feature = "toml")]
+fn toml(text: &str) -> Result<Tree, DocumentError> {
+    use toml::Value;
+
+    fn tree(value: Value) -> Tree {
+        match value {
+            Value::String(value) => Tree::Scalar(Some(value)),
+            Value::Integer(value) => Tree::Scalar(Some(value.to_string())),
+            Value::Float(value) => Tree::Scalar(Some(value.to_string())),
+            Value::Boolean(value) => Tree::Scalar(Some(value.to_string())),
+            Value::Datetime(value) => Tree::Scalar(Some(value.to_string())),
+            Value::Array(items) => Tree::List(items.into_iter().map(tree).collect()),
+            Value::Table(entries) => Tree::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, tree(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    let value = toml::from_str(text).map_err(parse_error(Format::Toml))?;
+    Ok(tree(value))
+}
+
+#[cfg(not(feature = "toml"))]
+fn toml(_: &str) -> Result<Tree, DocumentError> {
+    Err(DocumentError::UnsupportedFormat(Format::Toml))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "serde_json", feature = "serde_yaml", feature = "toml"))]
+    use crate::document::Document;
+    #[cfg(feature = "serde_json")]
+    use crate::document::DocumentError;
+    use crate::document::Format;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::from_path("vars.json"), Some(Format::Json));
+        assert_eq!(Format::from_path("config/vars.YML"), Some(Format::Yaml));
+        assert_eq!(Format::from_path("Cargo.toml"), Some(Format::Toml));
+        assert_eq!(Format::from_path("vars.env"), None);
+        assert_eq!(Format::from_path("json"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_json() {
+        let document = Document::parse(
+            r#"{"database": {"host": "db", "port": 5432, "replicas": ["a", "b"], "tls": true, "password": null}}"#,
+            Format::Json,
+            ".",
+        )
+        .unwrap();
+        assert_eq!(document.get("database.host"), Some("db"));
+        assert_eq!(document.get("database.port"), Some("5432"));
+        assert_eq!(document.get("database.replicas.1"), Some("b"));
+        assert_eq!(document.get("database.tls"), Some("true"));
+        assert_eq!(document.get("database.password"), None);
+        assert_eq!(document.get("database"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "serde_yaml")]
+    fn test_yaml() {
+        let document = Document::parse(
+            "database:\n  host: db\n  port: 5432\n  replicas:\n    - a\n    - b\n",
+            Format::Yaml,
+            "_",
+        )
+        .unwrap()
+        .uppercase();
+        assert_eq!(document.get("DATABASE_HOST"), Some("db"));
+        assert_eq!(document.get("DATABASE_PORT"), Some("5432"));
+        assert_eq!(document.get("DATABASE_REPLICAS_0"), Some("a"));
+        assert_eq!(document.get("database_host"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "toml")]
+    fn test_toml() {
+        let document = Document::parse(
+            "name = \"app\"\n[database]\nhost = \"db\"\nport = 5432\n",
+            Format::Toml,
+            "__",
+        )
+        .unwrap();
+        assert_eq!(document.get("name"), Some("app"));
+        assert_eq!(document.get("database__host"), Some("db"));
+        assert_eq!(document.get("database__port"), Some("5432"));
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_parse_error() {
+        let error = Document::parse("{", Format::Json, ".").unwrap_err();
+        assert!(matches!(
+            error,
+            DocumentError::Parse {
+                format: Format::Json,
+                ..
+            }
+        ));
+    }
+}
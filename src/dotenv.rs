@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::source::{SourceError, VariableSource};
+
+/// Why a `.env` file could not be loaded.
+#[derive(Debug)]
+pub enum DotEnvError {
+    Io(io::Error),
+    /// The file is not valid, `line` starts at 1.
+    Syntax {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for DotEnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DotEnvError::Io(error) => error.fmt(f),
+            DotEnvError::Syntax { line, message } => write!(f, "{} on line {}", message, line),
+        }
+    }
+}
+
+impl StdError for DotEnvError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            DotEnvError::Io(error) => error.source(),
+            DotEnvError::Syntax { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for DotEnvError {
+    fn from(error: io::Error) -> Self {
+        DotEnvError::Io(error)
+    }
+}
+
+/// Variables read from `.env` files.
+///
+/// Each line is a `NAME=value` assignment, optionally prefixed with `export`,
+/// and lines starting with `#` are comments. Values can be:
+///
+/// - unquoted, up to the end of the line or a ` #` comment, without the
+///   surrounding whitespace;
+/// - in single quotes, taken literally;
+/// - in double quotes, where `\n`, `\r`, `\t`, `\"`, `\\` and `\$` are escapes.
+///
+/// Quoted values can span several lines.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DotEnv {
+    variables: HashMap<String, String>,
+}
+
+impl DotEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, DotEnvError> {
+        let mut parser = DotEnvParser {
+            chars: text.chars().collect(),
+            index: 0,
+            line: 1,
+        };
+        let mut variables = HashMap::new();
+        while let Some((name, value)) = parser.assignment()? {
+            variables.insert(name, value);
+        }
+        Ok(Self { variables })
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, DotEnvError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Adds the variables of `other`, they override the ones that are already
+    /// set.
+    pub fn extend(&mut self, other: DotEnv) {
+        self.variables.extend(other.variables);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(String::as_str)
+    }
+}
+
+impl VariableSource for DotEnv {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        Ok(self.get(name).map(str::to_owned))
+    }
+}
+
+struct DotEnvParser {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+}
+
+impl DotEnvParser {
+    /// Returns `None` once the end of the file is reached.
+    fn assignment(&mut self) -> Result<Option<(String, String)>, DotEnvError> {
+        loop {
+            match self.peek() {
+                None => return Ok(None),
+                Some('#') => self.skip_line(),
+                Some(current) if current.is_whitespace() => {
+                    self.bump();
+                }
+                Some(_) => break,
+            }
+        }
+
+        let mut name = self.name();
+        if name == "export" && matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.skip_blanks();
+            name = self.name();
+        }
+        if name.is_empty() {
+            return Err(self.error("expected a variable name".to_owned()));
+        }
+        self.skip_blanks();
+        if self.peek() != Some('=') {
+            return Err(self.error(format!("expected '=' after {}", name)));
+        }
+        self.bump();
+        let blank = matches!(self.peek(), Some(' ') | Some('\t'));
+        self.skip_blanks();
+
+        let value = match self.peek() {
+            Some(quote @ '\'') | Some(quote @ '"') => {
+                let value = self.quoted(quote, &name)?;
+                self.skip_blanks();
+                match self.peek() {
+                    None | Some('\n') | Some('\r') => {}
+                    Some('#') => self.skip_line(),
+                    Some(_) => {
+                        return Err(self
+                            .error(format!("unexpected characters after the value of {}", name)))
+                    }
+                }
+                value
+            }
+            Some('#') if blank => {
+                self.skip_line();
+                String::new()
+            }
+            _ => self.unquoted(),
+        };
+        Ok(Some((name, value)))
+    }
+
+    fn name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(current) = self.peek() {
+            let valid = current == '_'
+                || current.is_ascii_alphabetic()
+                || !name.is_empty() && (current.is_ascii_digit() || current == '.');
+            if !valid {
+                break;
+            }
+            name.push(self.bump());
+        }
+        name
+    }
+
+    fn quoted(&mut self, quote: char, name: &str) -> Result<String, DotEnvError> {
+        let line = self.line;
+        self.bump();
+        let mut value = String::new();
+        loop {
+            let current = match self.peek() {
+                Some(current) => self.bump_and(current),
+                None => {
+                    return Err(DotEnvError::Syntax {
+                        line,
+                        message: format!("missing closing {} in the value of {}", quote, name),
+                    })
+                }
+            };
+            match current {
+                _ if current == quote => return Ok(value),
+                '\\' if quote == '"' => {
+                    let escaped = match self.peek() {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some(escaped @ '"') | Some(escaped @ '\\') | Some(escaped @ '$') => escaped,
+                        _ => {
+                            value.push('\\');
+                            continue;
+                        }
+                    };
+                    self.bump();
+                    value.push(escaped);
+                }
+                _ => value.push(current),
+            }
+        }
+    }
+
+    fn unquoted(&mut self) -> String {
+        let mut value = String::new();
+        while let Some(current) = self.peek() {
+            if current == '\n' || current == '#' && value.ends_with(char::is_whitespace) {
+                break;
+            }
+            value.push(self.bump());
+        }
+        if self.peek() == Some('#') {
+            self.skip_line();
+        }
+        value.trim_end().to_owned()
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.bump();
+        }
+    }
+
+    fn error(&self, message: String) -> DotEnvError {
+        DotEnvError::Syntax {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn bump(&mut self) -> char {
+        let current = self.peek().expect("bump is only called after a peek");
+        self.bump_and(current)
+    }
+
+    /// Moves past `current`, which has to be the next character.
+    fn bump_and(&mut self, current: char) -> char {
+        self.index += 1;
+        if current == '\n' {
+            self.line += 1;
+        }
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dotenv::{DotEnv, DotEnvError};
+
+    #[test]
+    fn test_parse() {
+        let env = DotEnv::parse(
+            r#"
+# A comment
+HOST=example.com
+export PORT = 8080   # the port
+EMPTY=
+COMMENTED= # nothing
+SPACED =  some value
+HASH=a#b
+SINGLE='literal \n $HOST'  # comment
+DOUBLE="line\nnext \"quoted\" \$HOST \x"
+MULTILINE="first
+second"
+SINGLE_MULTILINE='a
+b'
+app.name=demo
+"#,
+        )
+        .unwrap();
+        assert_eq!(env.get("HOST"), Some("example.com"));
+        assert_eq!(env.get("PORT"), Some("8080"));
+        assert_eq!(env.get("EMPTY"), Some(""));
+        assert_eq!(env.get("COMMENTED"), Some(""));
+        assert_eq!(env.get("SPACED"), Some("some value"));
+        assert_eq!(env.get("HASH"), Some("a#b"));
+        assert_eq!(env.get("SINGLE"), Some("literal \\n $HOST"));
+        assert_eq!(env.get("DOUBLE"), Some("line\nnext \"quoted\" $HOST \\x"));
+        assert_eq!(env.get("MULTILINE"), Some("first\nsecond"));
+        assert_eq!(env.get("SINGLE_MULTILINE"), Some("a\nb"));
+        assert_eq!(env.get("app.name"), Some("demo"));
+        assert_eq!(env.get("export"), None);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut env = DotEnv::parse("A=1\nB=2").unwrap();
+        env.extend(DotEnv::parse("B=3\nC=4").unwrap());
+        assert_eq!(env.get("A"), Some("1"));
+        assert_eq!(env.get("B"), Some("3"));
+        assert_eq!(env.get("C"), Some("4"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str| match DotEnv::parse(text).unwrap_err() {
+            DotEnvError::Syntax { line, message } => (line, message),
+            DotEnvError::Io(error) => panic!("unexpected error {}", error),
+        };
+        assert_eq!(
+            error("A=1\nB \"2\""),
+            (2, "expected '=' after B".to_owned())
+        );
+        assert_eq!(
+            error("A=1\nB=\"2\n\n"),
+            (2, "missing closing \" in the value of B".to_owned())
+        );
+        assert_eq!(
+            error("A='1' 2"),
+            (1, "unexpected characters after the value of A".to_owned())
+        );
+        assert_eq!(error("=1"), (1, "expected a variable name".to_owned()));
+    }
+}
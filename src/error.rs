@@ -0,0 +1,209 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use crate::source::SourceError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Where something is in a template. `line` and `column` start at 1 and count
+/// characters, `offset` starts at 0 and counts bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self {
+            line: 1,
+            column: 1,
+            offset: 0,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Everything that can go wrong while rendering a template. The position is
+/// always the one of the delimiter starting the offending variable.
+#[derive(Debug)]
+pub enum Error {
+    /// The input ended before the closing brace of a variable.
+    UnclosedBrace { name: String, position: Position },
+    /// The input ended before the closing marker of a variable, when custom
+    /// markers are used.
+    UnclosedMarker {
+        name: String,
+        close: String,
+        position: Position,
+    },
+    /// A character that can't appear where it was found in a variable.
+    InvalidCharacter {
+        name: String,
+        character: char,
+        position: Position,
+    },
+    /// The offset or length of `${VAR:offset:length}` is not an integer.
+    InvalidNumber {
+        name: String,
+        value: String,
+        position: Position,
+    },
+    /// A variable is not set and `MissingVariablePolicy::Fail` is used.
+    MissingVariable { name: String, position: Position },
+    /// A variable referenced as `${VAR:?message}` or `${VAR?message}` is not
+    /// set.
+    RequiredVariable {
+        name: String,
+        message: String,
+        position: Position,
+    },
+    /// The value of a variable is not valid unicode.
+    NonUnicodeValue { name: String, position: Position },
+    /// The template is not valid UTF-8 and strict UTF-8 checking is enabled.
+    /// The position is the one of the first invalid byte.
+    InvalidUtf8 { position: Position },
+    /// The variable source failed to look a variable up.
+    Source {
+        name: String,
+        position: Position,
+        source: SourceError,
+    },
+    /// A pipeline uses a filter that is not registered.
+    UnknownFilter {
+        name: String,
+        filter: String,
+        position: Position,
+    },
+    /// A filter of a pipeline failed, e.g. `base64-decode` on a value that
+    /// is not base64.
+    Filter {
+        name: String,
+        filter: String,
+        position: Position,
+        source: SourceError,
+    },
+    /// Reading the template or writing the output failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// The position of the offending variable, if the error is about one.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::UnclosedBrace { position, .. }
+            | Error::UnclosedMarker { position, .. }
+            | Error::InvalidCharacter { position, .. }
+            | Error::InvalidNumber { position, .. }
+            | Error::MissingVariable { position, .. }
+            | Error::RequiredVariable { position, .. }
+            | Error::NonUnicodeValue { position, .. }
+            | Error::InvalidUtf8 { position }
+            | Error::Source { position, .. }
+            | Error::UnknownFilter { position, .. }
+            | Error::Filter { position, .. } => Some(*position),
+            Error::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnclosedBrace { name, position } => write!(
+                f,
+                "Failed to parse a variable on {} missing a '}}' after '{}'",
+                position, name
+            ),
+            Error::UnclosedMarker {
+                name,
+                close,
+                position,
+            } => write!(
+                f,
+                "Failed to parse a variable on {} missing a '{}' after '{}'",
+                position, close, name
+            ),
+            Error::InvalidCharacter {
+                name,
+                character,
+                position,
+            } => write!(
+                f,
+                "Failed to parse variable {} with extra character '{}' on {}",
+                name, character, position
+            ),
+            Error::InvalidNumber {
+                name,
+                value,
+                position,
+            } => write!(
+                f,
+                "Invalid number '{}' for variable {} on {}",
+                value, name, position
+            ),
+            Error::MissingVariable { name, position } => {
+                write!(f, "The variable {} is not set on {}", name, position)
+            }
+            Error::RequiredVariable {
+                name,
+                message,
+                position,
+            } => write!(f, "{}: {} on {}", name, message, position),
+            Error::NonUnicodeValue { name, position } => write!(
+                f,
+                "The value of the variable {} on {} is not valid unicode",
+                name, position
+            ),
+            Error::InvalidUtf8 { position } => write!(f, "Invalid UTF-8 on {}", position),
+            Error::Source { name, position, .. } => write!(
+                f,
+                "Failed to read contents of variable {} on {}",
+                name, position
+            ),
+            Error::UnknownFilter {
+                name,
+                filter,
+                position,
+            } => write!(
+                f,
+                "Unknown filter '{}' for variable {} on {}",
+                filter, name, position
+            ),
+            Error::Filter {
+                name,
+                filter,
+                position,
+                ..
+            } => write!(
+                f,
+                "Filter '{}' failed for variable {} on {}",
+                filter, name, position
+            ),
+            Error::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Source { source, .. } | Error::Filter { source, .. } => Some(source.as_ref()),
+            Error::Io(error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
@@ -0,0 +1,127 @@
+/// A variable name, or a name prefix when written with a trailing `*`, e.g.
+/// `APP_*`.
+#[derive(Debug, Clone, PartialEq)]
+enum NamePattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl NamePattern {
+    fn new(pattern: &str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => NamePattern::Prefix(prefix.to_owned()),
+            None => NamePattern::Exact(pattern.to_owned()),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Exact(exact) => name == exact,
+            NamePattern::Prefix(prefix) => name.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// Decides which variables are substituted, the references to any other
+/// variable are written exactly as they appear in the template.
+///
+/// By default every variable is substituted. Once something is allowed only
+/// the allowed variables are, and denied variables never are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariableFilter {
+    allow: Option<Vec<NamePattern>>,
+    deny: Vec<NamePattern>,
+}
+
+impl VariableFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the variables matching `pattern`, either a name or a prefix
+    /// followed by `*`.
+    pub fn allow(mut self, pattern: &str) -> Self {
+        self.allow
+            .get_or_insert_with(Vec::new)
+            .push(NamePattern::new(pattern));
+        self
+    }
+
+    /// Denies the variables matching `pattern`, either a name or a prefix
+    /// followed by `*`.
+    pub fn deny(mut self, pattern: &str) -> Self {
+        self.deny.push(NamePattern::new(pattern));
+        self
+    }
+
+    /// Allows only the variables referenced in `format`, like the SHELL-FORMAT
+    /// argument of GNU envsubst, e.g. `'$HOST ${PORT}'`.
+    pub fn from_shell_format(format: &str, delimiter: char) -> Self {
+        let mut filter = Self {
+            allow: Some(Vec::new()),
+            deny: Vec::new(),
+        };
+        for reference in format.split(delimiter).skip(1) {
+            let reference = reference.strip_prefix('{').unwrap_or(reference);
+            let name: String = reference
+                .chars()
+                .take_while(|c| *c == '_' || c.is_alphanumeric())
+                .collect();
+            if !name.is_empty() {
+                filter = filter.allow(&name);
+            }
+        }
+        filter
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => allow.iter().any(|pattern| pattern.matches(name)),
+            None => true,
+        };
+        allowed && !self.deny.iter().any(|pattern| pattern.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::VariableFilter;
+
+    #[test]
+    fn test_everything_matches_by_default() {
+        let filter = VariableFilter::new();
+        assert!(filter.matches("HOST"));
+        assert!(filter.matches("remote_addr"));
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let filter = VariableFilter::new()
+            .allow("HOST")
+            .allow("APP_*")
+            .deny("APP_SECRET");
+        assert!(filter.matches("HOST"));
+        assert!(filter.matches("APP_PORT"));
+        assert!(!filter.matches("APP_SECRET"));
+        assert!(!filter.matches("HOSTNAME"));
+        assert!(!filter.matches("remote_addr"));
+    }
+
+    #[test]
+    fn test_deny_prefix() {
+        let filter = VariableFilter::new().deny("nginx_*");
+        assert!(filter.matches("HOST"));
+        assert!(!filter.matches("nginx_host"));
+    }
+
+    #[test]
+    fn test_shell_format() {
+        let filter = VariableFilter::from_shell_format("$HOST ${PORT},$$", '$');
+        assert!(filter.matches("HOST"));
+        assert!(filter.matches("PORT"));
+        assert!(!filter.matches("remote_addr"));
+
+        let filter = VariableFilter::from_shell_format("", '$');
+        assert!(!filter.matches("HOST"));
+    }
+}
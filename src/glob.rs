@@ -0,0 +1,307 @@
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[a-z_]`, or `[!a-z_]` when negated.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    /// `**/` in a path, any number of directories.
+    Directories,
+    /// `**` at the end of a path, anything including `/`.
+    Everything,
+}
+
+/// A shell pattern, where `*` matches any number of characters, `?` any single
+/// character, `[...]` any of the characters in brackets and `\` escapes the
+/// character after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    /// Whether `*`, `?` and `[...]` stop at `/`.
+    path: bool,
+}
+
+impl Glob {
+    /// A pattern for paths relative to a directory, separated by `/`. Only
+    /// `**` matches across directories, e.g. `**/*.tmpl` matches every
+    /// `.tmpl` file of a tree.
+    pub fn path(pattern: &str) -> Self {
+        Self {
+            tokens: tokenize(pattern, true),
+            path: true,
+        }
+    }
+
+    /// A pattern for any text, like in `case` or `${VAR#pattern}`, where `*`,
+    /// `?` and `[...]` also match `/`.
+    pub fn shell(pattern: &str) -> Self {
+        Self {
+            tokens: tokenize(pattern, false),
+            path: false,
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.match_starts(&text)[0]
+    }
+
+    /// `starts[j]` is whether the pattern matches `text[j..]`.
+    pub(crate) fn match_starts(&self, text: &[char]) -> Vec<bool> {
+        // matched[i][j] is whether the tokens from i match the text from j.
+        let mut matched = vec![vec![false; text.len() + 1]; self.tokens.len() + 1];
+        matched[self.tokens.len()][text.len()] = true;
+        // Whether the text from j is anything up to a `/`, then what the
+        // tokens after the current `**/` match.
+        let mut directories = vec![false; text.len() + 1];
+
+        for (i, token) in self.tokens.iter().enumerate().rev() {
+            for j in (0..=text.len()).rev() {
+                let current = text.get(j).copied();
+                let one = |matches: bool| matches && matched[i + 1][j + 1];
+                matched[i][j] = match (token, current) {
+                    (Token::Directories, _) => {
+                        directories[j] = match current {
+                            Some('/') => matched[i + 1][j + 1] || directories[j + 1],
+                            Some(_) => directories[j + 1],
+                            None => false,
+                        };
+                        matched[i + 1][j] || directories[j]
+                    }
+                    (Token::Everything, None) => matched[i + 1][j],
+                    (Token::Everything, Some(_)) => matched[i + 1][j] || matched[i][j + 1],
+                    (_, None) => matches!(token, Token::Star) && matched[i + 1][j],
+                    (Token::Star, Some(current)) => {
+                        matched[i + 1][j] || self.crosses(current) && matched[i][j + 1]
+                    }
+                    (_, Some(current)) => one(self.matches_char(token, current)),
+                };
+            }
+        }
+        matched.swap_remove(0)
+    }
+
+    /// `ends[j]` is whether the pattern matches `text[..j]`.
+    pub(crate) fn match_ends(&self, text: &[char]) -> Vec<bool> {
+        let count = self.tokens.len();
+        // active[i] is whether the tokens before i match the text read so far,
+        // and in_directory[i] whether the `**/` at i is in the middle of a
+        // directory name.
+        let mut active = vec![false; count + 1];
+        let mut in_directory = vec![false; count];
+        active[0] = true;
+        self.skip_empty(&mut active);
+
+        let mut ends = Vec::with_capacity(text.len() + 1);
+        ends.push(active[count]);
+        for &current in text {
+            let mut next = vec![false; count + 1];
+            let mut next_in_directory = vec![false; count];
+            for (i, token) in self.tokens.iter().enumerate() {
+                match token {
+                    Token::Directories if active[i] || in_directory[i] => {
+                        if current == '/' {
+                            next[i] = true;
+                        } else {
+                            next_in_directory[i] = true;
+                        }
+                    }
+                    _ if !active[i] => {}
+                    Token::Star => next[i] = self.crosses(current) || next[i],
+                    Token::Everything => next[i] = true,
+                    _ => next[i + 1] = self.matches_char(token, current) || next[i + 1],
+                }
+            }
+            self.skip_empty(&mut next);
+            active = next;
+            in_directory = next_in_directory;
+            ends.push(active[count]);
+        }
+        ends
+    }
+
+    /// Marks the tokens after the ones that can match nothing as active too.
+    fn skip_empty(&self, active: &mut [bool]) {
+        for (i, token) in self.tokens.iter().enumerate() {
+            if active[i] && matches!(token, Token::Star | Token::Everything | Token::Directories) {
+                active[i + 1] = true;
+            }
+        }
+    }
+
+    /// Whether a token that matches a single character matches `current`.
+    fn matches_char(&self, token: &Token, current: char) -> bool {
+        match token {
+            Token::Char(expected) => current == *expected,
+            Token::Any => self.crosses(current),
+            Token::Class { negated, ranges } => {
+                let in_class = ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&current));
+                in_class != *negated && self.crosses(current)
+            }
+            Token::Star | Token::Directories | Token::Everything => false,
+        }
+    }
+
+    /// Whether `*`, `?` and `[...]` can match `current`.
+    fn crosses(&self, current: char) -> bool {
+        !(self.path && current == '/')
+    }
+}
+
+fn tokenize(pattern: &str, path: bool) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let at_segment_start = index == 0 || chars[index - 1] == '/';
+        match chars[index] {
+            '*' if path && at_segment_start && chars.get(index + 1) == Some(&'*') => {
+                match chars.get(index + 2) {
+                    Some('/') => {
+                        tokens.push(Token::Directories);
+                        index += 3;
+                        continue;
+                    }
+                    None => {
+                        tokens.push(Token::Everything);
+                        index += 2;
+                        continue;
+                    }
+                    // Not a whole segment, so just two stars.
+                    Some(_) => tokens.push(Token::Star),
+                }
+            }
+            '*' => tokens.push(Token::Star),
+            '?' => tokens.push(Token::Any),
+            '[' => match class(&chars[index + 1..]) {
+                Some((token, length)) => {
+                    tokens.push(token);
+                    index += length + 1;
+                    continue;
+                }
+                None => tokens.push(Token::Char('[')),
+            },
+            '\\' if index + 1 < chars.len() => {
+                index += 1;
+                tokens.push(Token::Char(chars[index]));
+            }
+            current => tokens.push(Token::Char(current)),
+        }
+        index += 1;
+    }
+    tokens
+}
+
+/// Parses what follows a `[`, returns `None` if there is no closing `]` so the
+/// `[` is taken literally like in shells.
+fn class(chars: &[char]) -> Option<(Token, usize)> {
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    let mut index = if negated { 1 } else { 0 };
+    let mut ranges = Vec::new();
+    loop {
+        let start = *chars.get(index)?;
+        // A `]` right after the `[` is part of the class.
+        if start == ']' && !ranges.is_empty() {
+            return Some((Token::Class { negated, ranges }, index + 1));
+        }
+        match (chars.get(index + 1), chars.get(index + 2)) {
+            (Some('-'), Some(end)) if *end != ']' => {
+                ranges.push((start, *end));
+                index += 3;
+            }
+            _ => {
+                ranges.push((start, start));
+                index += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::Glob;
+
+    #[test]
+    fn test_path_glob() {
+        let glob = Glob::path("**/*.tmpl");
+        assert!(glob.matches("app.tmpl"));
+        assert!(glob.matches("config/app.tmpl"));
+        assert!(glob.matches("a/b/c/.tmpl"));
+        assert!(!glob.matches("app.tmpl.bak"));
+        assert!(!glob.matches("app.txt"));
+
+        let glob = Glob::path("*.tmpl");
+        assert!(glob.matches("app.tmpl"));
+        assert!(!glob.matches("config/app.tmpl"));
+
+        let glob = Glob::path("config/**");
+        assert!(glob.matches("config/app.tmpl"));
+        assert!(glob.matches("config/a/b"));
+        assert!(!glob.matches("other/app.tmpl"));
+
+        let glob = Glob::path("a/**/b/*.conf");
+        assert!(glob.matches("a/b/x.conf"));
+        assert!(glob.matches("a/x/y/b/x.conf"));
+        assert!(!glob.matches("a/x/y/b/c/x.conf"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let glob = Glob::path("file?.[ch]");
+        assert!(glob.matches("file1.c"));
+        assert!(glob.matches("fileX.h"));
+        assert!(!glob.matches("file10.c"));
+        assert!(!glob.matches("file/.c"));
+
+        let glob = Glob::path("[!a-c]*");
+        assert!(glob.matches("data"));
+        assert!(!glob.matches("backup"));
+
+        let glob = Glob::path("[]x]\\*[");
+        assert!(glob.matches("]*["));
+        assert!(glob.matches("x*["));
+        assert!(!glob.matches("xa["));
+    }
+
+    #[test]
+    fn test_shell_glob() {
+        let glob = Glob::shell("*.tmpl");
+        assert!(glob.matches("config/app.tmpl"));
+        assert!(Glob::shell("a?c").matches("a/c"));
+        assert!(Glob::shell("[/]").matches("/"));
+        assert!(!Glob::path("[/]").matches("/"));
+    }
+
+    #[test]
+    fn test_match_ends() {
+        let text: Vec<char> = "a/b.c/d.c".chars().collect();
+        let ends = |glob: Glob| {
+            let ends = glob.match_ends(&text);
+            (0..=text.len()).filter(|&j| ends[j]).collect::<Vec<_>>()
+        };
+        assert_eq!(ends(Glob::shell("*.c")), vec![5, 9]);
+        assert_eq!(ends(Glob::shell("a*")), (1..=9).collect::<Vec<_>>());
+        assert_eq!(ends(Glob::shell("")), vec![0]);
+        assert_eq!(ends(Glob::path("*.c")), Vec::<usize>::new());
+        assert_eq!(ends(Glob::path("a/**/*.c")), vec![5, 9]);
+        assert_eq!(ends(Glob::path("a/**")), (2..=9).collect::<Vec<_>>());
+
+        // Both ways of matching agree on the whole text.
+        for pattern in &["**/*.c", "a/**/d.c", "*/*", "a/*", "[a-z]/**"] {
+            let glob = Glob::path(pattern);
+            assert_eq!(
+                glob.match_ends(&text)[text.len()],
+                glob.match_starts(&text)[0],
+                "{}",
+                pattern
+            );
+        }
+    }
+}
@@ -1,2 +1,32 @@
+pub mod document;
+pub mod dotenv;
+pub mod error;
+pub mod filter;
+pub mod glob;
 pub mod parser;
-pub use crate::parser::Parser;
+pub mod pipeline;
+mod render;
+pub mod source;
+pub mod syntax;
+pub mod template;
+pub use crate::document::{Document, DocumentError, Format};
+pub use crate::dotenv::{DotEnv, DotEnvError};
+pub use crate::error::{Error, Position, Result};
+pub use crate::filter::VariableFilter;
+pub use crate::glob::Glob;
+pub use crate::parser::{default_delimiter, Parser, ParserBuilder};
+pub use crate::pipeline::{ValueFilter, ValueFilters};
+pub use crate::render::MissingVariablePolicy;
+pub use crate::source::{Env, Layered, SourceError, VariableSource};
+pub use crate::syntax::{
+    Escape, Expansion, FilterCall, Identifiers, Markers, Node, Operator, Span, Variable,
+};
+pub use crate::template::{substitute, substitute_with, Template};
+
+/// Shared with the binary, not part of the API.
+#[doc(hidden)]
+pub mod private {
+    pub fn escape_json(value: &str) -> String {
+        crate::pipeline::escape_json(value)
+    }
+}
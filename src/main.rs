@@ -1,39 +1,960 @@
-use std::fs::File;
-use std::io::{stdin, stdout, BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, sink, stdin, stdout, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use structopt::StructOpt;
 
-use envsubst::Parser;
+use envsubst::{
+    default_delimiter, private, Document, DotEnv, Env, Escape, Glob, Identifiers, Layered, Markers,
+    MissingVariablePolicy, Parser, ParserBuilder, Variable, VariableFilter,
+};
 
 #[derive(Debug, StructOpt)]
 struct Config {
     #[structopt(long, short)]
     pub input: Option<PathBuf>,
+    #[structopt(
+        name = "FILE",
+        help = "Templates to render after --input, one after the other"
+    )]
+    pub files: Vec<PathBuf>,
     #[structopt(long, short)]
     pub output: Option<PathBuf>,
-    #[structopt(long, short, help = "Fail if a variable could not be found")]
+    #[structopt(
+        long,
+        conflicts_with_all = &["output", "list-variables"],
+        help = "Replace every input file with its rendered version"
+    )]
+    pub in_place: bool,
+    #[structopt(
+        long,
+        value_name = "SUFFIX",
+        requires = "in-place",
+        parse(try_from_str = parse_suffix),
+        help = "Keep a copy of every file replaced by --in-place, named after it with SUFFIX appended"
+    )]
+    pub backup_suffix: Option<String>,
+    #[structopt(
+        long,
+        value_name = "DIR",
+        requires = "output-dir",
+        conflicts_with_all = &["input", "FILE", "output", "in-place", "list-variables"],
+        help = "Render the templates of a directory tree into --output-dir"
+    )]
+    pub input_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "DIR",
+        requires = "input-dir",
+        help = "Where the tree of --input-dir is rendered"
+    )]
+    pub output_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "PATTERN",
+        requires = "input-dir",
+        help = "Which files of --input-dir are templates, relative to it [default: **/*SUFFIX]"
+    )]
+    pub glob: Option<String>,
+    #[structopt(
+        long,
+        requires = "input-dir",
+        help = "Suffix removed from the names of the rendered templates of --input-dir [default: .tmpl]"
+    )]
+    pub suffix: Option<String>,
+    #[structopt(
+        long,
+        requires = "input-dir",
+        help = "Copy the files of --input-dir that are not templates as they are"
+    )]
+    pub copy_other: bool,
+    #[structopt(
+        long,
+        short,
+        conflicts_with = "missing",
+        help = "Fail if a variable could not be found, same as --missing fail"
+    )]
     pub fail: bool,
+    #[structopt(
+        long,
+        possible_values = &["empty", "fail", "keep", "warn"],
+        help = "What to do with variables that could not be found [default: empty]"
+    )]
+    pub missing: Option<MissingVariablePolicy>,
     #[structopt(long, short, help = "Variable delimiter")]
     pub delimiter: Option<char>,
+    #[structopt(
+        long,
+        value_name = "MARKER",
+        requires = "close",
+        conflicts_with_all = &["delimiter", "braced-only"],
+        parse(try_from_str = parse_marker),
+        help = "Start variables with this instead of '$' and '${', e.g. '{{', '@' or '%'"
+    )]
+    pub open: Option<String>,
+    #[structopt(
+        long,
+        value_name = "MARKER",
+        requires = "open",
+        parse(try_from_str = parse_marker),
+        help = "End variables started with --open with this, e.g. '}}', '@' or '%'"
+    )]
+    pub close: Option<String>,
+    #[structopt(
+        long,
+        requires = "open",
+        help = "Allow whitespace inside --open and --close, e.g. '{{ VAR }}'"
+    )]
+    pub whitespace: bool,
+    #[structopt(
+        long,
+        default_value = "none",
+        possible_values = &["none", "double", "backslash", "both"],
+        help = "How a literal delimiter is written: as '$$', as '\\$' or both"
+    )]
+    pub escape: Escape,
+    #[structopt(
+        long,
+        default_value = "ascii",
+        possible_values = &["ascii", "unicode", "extended"],
+        help = "Characters allowed in variable names, extended also allows '.' and '-'"
+    )]
+    pub identifiers: Identifiers,
+    #[structopt(
+        long,
+        help = "Fail on input that is not valid UTF-8 instead of copying it as it is"
+    )]
+    pub strict_utf8: bool,
+    #[structopt(long, help = "Only substitute ${VAR}, a bare $VAR is written as it is")]
+    pub braced_only: bool,
+    #[structopt(
+        long,
+        help = "Only change the case of ASCII letters with ${VAR^^} and ${VAR,,}"
+    )]
+    pub ascii_case: bool,
+    #[structopt(
+        long,
+        help = "Parse filter pipelines like ${VAR | trim | upper} or ${VAR | default(\"none\")}"
+    )]
+    pub pipelines: bool,
+    #[structopt(
+        long,
+        value_name = "SHELL-FORMAT",
+        help = "Only substitute the variables referenced in SHELL-FORMAT, e.g. '$HOST $PORT'"
+    )]
+    pub variables: Option<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Only substitute this variable, or the ones starting with a prefix like 'APP_*'"
+    )]
+    pub allow: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Never substitute this variable, or the ones starting with a prefix like 'APP_*'"
+    )]
+    pub deny: Vec<String>,
+    #[structopt(
+        long,
+        help = "Print the variables referenced by the input instead of substituting them"
+    )]
+    pub list_variables: bool,
+    #[structopt(
+        long,
+        requires = "list-variables",
+        help = "Print the variables as a JSON array"
+    )]
+    pub json: bool,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        number_of_values = 1,
+        help = "Read variables from a .env file, they override the environment and the previous files"
+    )]
+    pub env_file: Vec<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        number_of_values = 1,
+        help = "Read default values from a JSON, YAML or TOML file, depending on its extension"
+    )]
+    pub vars_file: Vec<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "SEPARATOR",
+        default_value = ".",
+        help = "Joins the keys of nested values of --vars-file, e.g. '.' for ${database.host}"
+    )]
+    pub vars_separator: String,
+    #[structopt(
+        long,
+        help = "Uppercase the names of the variables of --vars-file, e.g. for ${DATABASE_HOST}"
+    )]
+    pub vars_uppercase: bool,
+    #[structopt(
+        long,
+        short = "e",
+        value_name = "KEY=VALUE",
+        number_of_values = 1,
+        parse(try_from_str = parse_assignment),
+        help = "Set a variable, it overrides every other source"
+    )]
+    pub set: Vec<(String, String)>,
+    #[structopt(long, help = "Ignore the environment variables")]
+    pub no_env: bool,
 }
 
-fn main() -> Result<()> {
-    let config: Config = Config::from_args();
-    let input: Box<dyn BufRead> = if let Some(input_file) = config.input {
-        Box::new(BufReader::new(File::open(input_file)?))
-    } else {
-        eprintln!("No input file specified, falling back to stdin");
-        Box::new(BufReader::new(stdin()))
-    };
-    let output: Box<dyn Write> = if let Some(output_file) = config.output {
-        Box::new(File::create(output_file)?)
-    } else {
-        eprintln!("No output file specified, falling back to stdout");
-        Box::new(stdout())
+fn parse_suffix(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err("the backup suffix can't be empty".to_owned());
+    }
+    Ok(value.to_owned())
+}
+
+fn parse_marker(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err("markers can't be empty".to_owned());
+    }
+    Ok(value.to_owned())
+}
+
+fn parse_assignment(value: &str) -> Result<(String, String), String> {
+    match value.find('=') {
+        Some(0) | None => Err(format!("expected KEY=VALUE, got '{}'", value)),
+        Some(index) => Ok((value[..index].to_owned(), value[index + 1..].to_owned())),
+    }
+}
+
+impl Config {
+    fn inputs(&self) -> Vec<PathBuf> {
+        self.input.iter().chain(&self.files).cloned().collect()
+    }
+
+    fn missing(&self) -> MissingVariablePolicy {
+        if self.fail {
+            return MissingVariablePolicy::Fail;
+        }
+        self.missing.unwrap_or_default()
+    }
+
+    fn filter(&self) -> VariableFilter {
+        let delimiter = self.delimiter.unwrap_or_else(default_delimiter);
+        let filter = match &self.variables {
+            Some(format) => VariableFilter::from_shell_format(format, delimiter),
+            None => VariableFilter::new(),
+        };
+        let filter = self
+            .allow
+            .iter()
+            .fold(filter, |filter, pattern| filter.allow(pattern));
+        self.deny
+            .iter()
+            .fold(filter, |filter, pattern| filter.deny(pattern))
+    }
+
+    /// The `--vars-file` files, the environment, the `--env-file` files and
+    /// then `--set`, each one overriding the previous ones.
+    fn load_variables(&self) -> Result<Layered> {
+        let mut sources = Layered::new();
+        for path in &self.vars_file {
+            let mut document = Document::from_path(path, &self.vars_separator)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            if self.vars_uppercase {
+                document = document.uppercase();
+            }
+            sources = sources.layer(&path.display().to_string(), document);
+        }
+        if !self.no_env {
+            sources = sources.layer("environment", Env);
+        }
+        for path in &self.env_file {
+            let dotenv = DotEnv::from_path(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            sources = sources.layer(&path.display().to_string(), dotenv);
+        }
+        let set: HashMap<String, String> = self.set.iter().cloned().collect();
+        Ok(sources.layer("--set", set))
+    }
+
+    fn parser<'a, R, W>(
+        &self,
+        sources: &'a Layered,
+        input: R,
+        output: W,
+    ) -> Parser<R, W, &'a Layered>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        let mut builder = ParserBuilder::new()
+            .source(sources)
+            .missing(self.missing())
+            .on_warning(|variable| {
+                eprintln!(
+                    "The variable {} on {} is not set",
+                    variable.name, variable.span.start
+                )
+            })
+            .escape(self.escape)
+            .identifiers(self.identifiers)
+            .strict_utf8(self.strict_utf8)
+            .braced_only(self.braced_only)
+            .ascii_case(self.ascii_case)
+            .pipelines(self.pipelines)
+            .filter(self.filter());
+        if let Some(delimiter) = self.delimiter {
+            builder = builder.delimiter(delimiter);
+        }
+        if let (Some(open), Some(close)) = (&self.open, &self.close) {
+            builder = builder.markers(Markers::new(open, close).whitespace(self.whitespace));
+        }
+        builder.build(input, output)
+    }
+
+    /// Renders the inputs one after the other, or stdin if there is none.
+    fn render<W: Write>(
+        &self,
+        sources: &Layered,
+        inputs: &[PathBuf],
+        output: &mut W,
+    ) -> Result<()> {
+        if inputs.is_empty() {
+            eprintln!("No input file specified, falling back to stdin");
+            self.parser(sources, BufReader::new(stdin()), &mut *output)
+                .process()?;
+        }
+        for path in inputs {
+            let input = open(path)?;
+            self.parser(sources, input, &mut *output)
+                .process()
+                .with_context(|| format!("Failed to render {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn render_in_place(&self, sources: &Layered, path: &Path) -> Result<()> {
+        let input = open(path)?;
+        if let Some(suffix) = &self.backup_suffix {
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(suffix);
+            // Copying a file onto itself would truncate it.
+            if let Ok(resolved) = fs::canonicalize(&backup) {
+                if resolved == fs::canonicalize(path)? {
+                    bail!("The backup of {} would replace it", path.display());
+                }
+            }
+            fs::copy(path, &backup)
+                .with_context(|| format!("Failed to back {} up", path.display()))?;
+        }
+        let permissions = fs::metadata(path)?.permissions();
+        write_atomically(path, Some(permissions), |output| {
+            self.parser(sources, input, output)
+                .process()
+                .with_context(|| format!("Failed to render {}", path.display()))
+        })
+    }
+
+    fn render_tree(&self, sources: &Layered, input_dir: &Path, output_dir: &Path) -> Result<()> {
+        let suffix = self.suffix.as_deref().unwrap_or(".tmpl");
+        let glob = match &self.glob {
+            Some(pattern) => Glob::path(pattern),
+            None => Glob::path(&format!("**/*{}", suffix)),
+        };
+        fs::create_dir_all(output_dir)
+            .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+        // The output directory may be inside the input one.
+        let skip = fs::canonicalize(output_dir)?;
+
+        let mut files = Vec::new();
+        walk(input_dir, Path::new(""), &skip, &mut files)?;
+        for relative in files {
+            let source = input_dir.join(&relative);
+            let slashed = relative
+                .iter()
+                .map(|component| component.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let template = glob.matches(&slashed);
+            if !template && !self.copy_other {
+                continue;
+            }
+
+            let mut destination = output_dir.join(&relative);
+            if template {
+                let name = relative.file_name().unwrap_or_default().to_string_lossy();
+                if let Some(stripped) = name.strip_suffix(suffix) {
+                    if !stripped.is_empty() {
+                        destination.set_file_name(stripped);
+                    }
+                }
+            }
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+
+            let permissions = fs::metadata(&source)?.permissions();
+            let mut input = open(&source)?;
+            write_atomically(&destination, Some(permissions), |output| {
+                if template {
+                    self.parser(sources, input, output)
+                        .process()
+                        .with_context(|| format!("Failed to render {}", source.display()))
+                } else {
+                    io::copy(&mut input, output)?;
+                    Ok(())
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn list_variables(&self, sources: &Layered, inputs: &[PathBuf]) -> Result<()> {
+        let filter = self.filter();
+        let mut variables = Vec::new();
+        if inputs.is_empty() {
+            eprintln!("No input file specified, falling back to stdin");
+            let found = self
+                .parser(sources, BufReader::new(stdin()), sink())
+                .variables()?;
+            variables.extend(found.into_iter().map(|variable| (None, variable)));
+        }
+        for path in inputs {
+            let found = self
+                .parser(sources, open(path)?, sink())
+                .variables()
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            variables.extend(
+                found
+                    .into_iter()
+                    .map(|variable| (Some(path.as_path()), variable)),
+            );
+        }
+        variables.retain(|(_, variable)| filter.matches(&variable.name));
+
+        let mut output: Box<dyn Write> = match &self.output {
+            Some(output_file) => Box::new(File::create(output_file)?),
+            None => Box::new(stdout()),
+        };
+        if self.json {
+            print_variables_json(&variables, &mut output)?;
+        } else {
+            print_variables(&variables, inputs.len() > 1, &mut output)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+}
+
+/// Adds the files under `directory` to `files`, sorted and relative to the
+/// directory `walk` was first called with. Symbolic links to directories and
+/// the `skip` directory aren't followed.
+fn walk(directory: &Path, relative: &Path, skip: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let path = directory.join(relative);
+    let mut entries = fs::read_dir(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            if fs::canonicalize(entry.path())? != skip {
+                walk(directory, &relative, skip, files)?;
+            }
+        } else if entry.path().is_file() {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Writes `path` through a temporary file next to it, which only replaces it
+/// once `write` succeeded, so `path` is never left half written. The file gets
+/// `permissions` if there are some. Symlinks are followed, and anything but a
+/// regular file, e.g. a FIFO or `/dev/stdout`, is written to directly as a
+/// rename would replace it.
+fn write_atomically<F>(path: &Path, permissions: Option<Permissions>, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => path.to_owned(),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to resolve {}", path.display()))
+        }
     };
-    let mut parser = Parser::new(input, output, config.fail, config.delimiter);
-    parser.process()?;
+    let regular = fs::symlink_metadata(&path)
+        .map(|metadata| metadata.file_type().is_file())
+        .unwrap_or(true);
+    if !regular {
+        let mut file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        return write(&mut file);
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+    let temporary = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        process::id()
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Created with the permissions already, so what is written is never more
+    // readable than the file it replaces.
+    #[cfg(unix)]
+    if let Some(permissions) = &permissions {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(permissions.mode());
+    }
+    let mut file = options
+        .open(&temporary)
+        .with_context(|| format!("Failed to create {}", temporary.display()))?;
+
+    let result = permissions
+        .map_or(Ok(()), |permissions| file.set_permissions(permissions))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| write(&mut file))
+        .and_then(|()| {
+            file.sync_all()?;
+            fs::rename(&temporary, &path)
+                .with_context(|| format!("Failed to replace {}", path.display()))
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Writes one variable per line as `line:column<TAB>name`, followed by the
+/// operator and its word or the filters of its pipeline when there are some.
+/// The lines start with the file of the variable when `with_files` is set.
+fn print_variables<W: Write>(
+    variables: &[(Option<&Path>, Variable)],
+    with_files: bool,
+    output: &mut W,
+) -> Result<()> {
+    for (path, variable) in variables {
+        if let (true, Some(path)) = (with_files, path) {
+            write!(output, "{}:", path.display())?;
+        }
+        let start = variable.span.start;
+        write!(output, "{}:{}\t{}", start.line, start.column, variable.name)?;
+        if let Some(expansion) = &variable.expansion {
+            write!(output, "\t{}", expansion)?;
+            output.write_all(&expansion.raw_word)?;
+        }
+        if !variable.filters.is_empty() {
+            let filters: Vec<String> = variable
+                .filters
+                .iter()
+                .map(|filter| {
+                    if filter.arguments.is_empty() {
+                        return format!("| {}", filter.name);
+                    }
+                    let arguments: Vec<String> = filter
+                        .arguments
+                        .iter()
+                        .map(|argument| json_string(argument))
+                        .collect();
+                    format!("| {}({})", filter.name, arguments.join(", "))
+                })
+                .collect();
+            write!(output, "\t{}", filters.join(" "))?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+fn print_variables_json<W: Write>(
+    variables: &[(Option<&Path>, Variable)],
+    output: &mut W,
+) -> Result<()> {
+    write!(output, "[")?;
+    for (index, (path, variable)) in variables.iter().enumerate() {
+        if index > 0 {
+            write!(output, ",")?;
+        }
+        let file = match path {
+            Some(path) => json_string(&path.to_string_lossy()),
+            None => "null".to_owned(),
+        };
+        let (operator, word) = match &variable.expansion {
+            Some(expansion) => (
+                json_string(&expansion.to_string()),
+                json_string(&String::from_utf8_lossy(&expansion.raw_word)),
+            ),
+            None => ("null".to_owned(), "null".to_owned()),
+        };
+        let filters: Vec<String> = variable
+            .filters
+            .iter()
+            .map(|filter| {
+                let arguments: Vec<String> = filter
+                    .arguments
+                    .iter()
+                    .map(|argument| json_string(argument))
+                    .collect();
+                format!(
+                    "{{\"name\":{},\"arguments\":[{}]}}",
+                    json_string(&filter.name),
+                    arguments.join(",")
+                )
+            })
+            .collect();
+        let start = variable.span.start;
+        write!(
+            output,
+            "{{\"file\":{},\"name\":{},\"operator\":{},\"word\":{},\"filters\":[{}],\"line\":{},\"column\":{},\"offset\":{}}}",
+            file,
+            json_string(&variable.name),
+            operator,
+            word,
+            filters.join(","),
+            start.line,
+            start.column,
+            start.offset
+        )?;
+    }
+    writeln!(output, "]")?;
     Ok(())
 }
+
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", private::escape_json(value))
+}
+
+fn main() -> Result<()> {
+    let config: Config = Config::from_args();
+    let sources = config.load_variables()?;
+    let inputs = config.inputs();
+    if config.in_place {
+        if inputs.is_empty() {
+            bail!("--in-place needs at least one input file");
+        }
+        for path in &inputs {
+            config.render_in_place(&sources, path)?;
+        }
+        return Ok(());
+    }
+    if config.list_variables {
+        return config.list_variables(&sources, &inputs);
+    }
+
+    if let (Some(input_dir), Some(output_dir)) = (&config.input_dir, &config.output_dir) {
+        return config.render_tree(&sources, input_dir, output_dir);
+    }
+
+    match &config.output {
+        Some(output_file) => {
+            let permissions = fs::metadata(output_file)
+                .map(|metadata| metadata.permissions())
+                .ok();
+            write_atomically(output_file, permissions, |output| {
+                config.render(&sources, &inputs, output)
+            })
+        }
+        None => {
+            eprintln!("No output file specified, falling back to stdout");
+            let stdout = stdout();
+            let mut output = stdout.lock();
+            config.render(&sources, &inputs, &mut output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::{sink, Write};
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use envsubst::{Layered, ParserBuilder};
+    use structopt::StructOpt;
+
+    use crate::{print_variables, print_variables_json, walk, write_atomically, Config};
+
+    /// An empty directory only used by the test `name`.
+    fn directory(name: &str) -> PathBuf {
+        let directory = temp_dir().join(format!("envsubst-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_vars_file() {
+        let directory = directory("vars-file");
+        let vars = directory.join("vars.json");
+        let template = directory.join("template");
+        fs::write(&vars, r#"{"database": {"host": "db", "port": 5432}}"#).unwrap();
+
+        let render = |text: &str, arguments: &[&str]| {
+            fs::write(&template, text).unwrap();
+            let config = Config::from_iter(
+                ["envsubst", "--no-env", "--vars-file"]
+                    .iter()
+                    .copied()
+                    .chain(Some(vars.to_str().unwrap()))
+                    .chain(arguments.iter().copied()),
+            );
+            let sources = config.load_variables().unwrap();
+            let mut output = Vec::new();
+            config
+                .render(&sources, std::slice::from_ref(&template), &mut output)
+                .map_err(|error| format!("{:#}", error))?;
+            Ok::<_, String>(String::from_utf8(output).unwrap())
+        };
+        assert_eq!(
+            render("h=${database.host} p=${database.port}", &[]),
+            Ok("h=db p=5432".to_owned())
+        );
+        assert_eq!(
+            render(
+                "h=${database_host} p=${database_port}",
+                &["--vars-separator", "_"]
+            ),
+            Ok("h=db p=5432".to_owned())
+        );
+        assert_eq!(
+            render(
+                "port=${PORT-8080} host=$HOST. db=${database.host}",
+                &["--set", "HOST=h"]
+            ),
+            Ok("port=8080 host=h. db=db".to_owned())
+        );
+        assert_eq!(
+            render(
+                "port=${PORT-8080} host=$HOST.",
+                &["--set", "HOST=h", "--fail"]
+            ),
+            Ok("port=8080 host=h.".to_owned())
+        );
+        assert_eq!(
+            render(
+                "$database.host ${database-x}",
+                &["--identifiers", "extended"]
+            ),
+            Ok("db ".to_owned())
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_suffix() {
+        let arguments = ["envsubst", "--in-place", "--backup-suffix", ""];
+        assert!(Config::from_iter_safe(&arguments).is_err());
+
+        let directory = directory("backup");
+        let path = directory.join("e.conf");
+        fs::write(&path, "keep me $X\n").unwrap();
+        std::os::unix::fs::symlink(&path, directory.join("e.conf.bak")).unwrap();
+        let config = Config::from_iter(&["envsubst", "--in-place", "--backup-suffix", ".bak"]);
+        let error = config.render_in_place(&Layered::new(), &path).unwrap_err();
+        assert!(error.to_string().contains("would replace it"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me $X\n");
+
+        fs::remove_file(directory.join("e.conf.bak")).unwrap();
+        let sources = Layered::new().layer("test", |_: &str| Some("x".to_owned()));
+        config.render_in_place(&sources, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me x\n");
+        assert_eq!(
+            fs::read_to_string(directory.join("e.conf.bak")).unwrap(),
+            "keep me $X\n"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        assert!(Config::from_iter_safe(&["envsubst"]).is_ok());
+        assert!(Config::from_iter_safe(&["envsubst", "--suffix", ".in"]).is_err());
+        assert!(Config::from_iter_safe(&["envsubst", "--glob", "*.in"]).is_err());
+
+        let directory = directory("tree");
+        let input = directory.join("input");
+        fs::create_dir_all(input.join("sub")).unwrap();
+        fs::write(input.join("app.conf.tmpl"), "app=$X").unwrap();
+        fs::write(input.join(".tmpl"), "dot=$X").unwrap();
+        fs::write(input.join("sub/run.sh.tmpl"), "run $X").unwrap();
+        fs::write(input.join("plain.txt"), "plain $X").unwrap();
+        let mode = fs::Permissions::from_mode(0o750);
+        fs::set_permissions(input.join("sub/run.sh.tmpl"), mode).unwrap();
+        let output = input.join("output");
+
+        let render_tree = |arguments: &[&str]| {
+            let directories = [
+                "--input-dir",
+                input.to_str().unwrap(),
+                "--output-dir",
+                output.to_str().unwrap(),
+            ];
+            let config = Config::from_iter(
+                ["envsubst"]
+                    .iter()
+                    .chain(&directories)
+                    .chain(arguments)
+                    .copied(),
+            );
+            let sources = Layered::new().layer("test", |_: &str| Some("1".to_owned()));
+            config.render_tree(&sources, &input, &output).unwrap();
+        };
+        let files = || {
+            let mut files = Vec::new();
+            walk(&output, Path::new(""), Path::new(""), &mut files).unwrap();
+            files
+        };
+
+        render_tree(&[]);
+        assert_eq!(
+            files(),
+            [".tmpl", "app.conf", "sub/run.sh"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("app.conf")).unwrap(),
+            "app=1"
+        );
+        assert_eq!(fs::read_to_string(output.join(".tmpl")).unwrap(), "dot=1");
+        let metadata = fs::metadata(output.join("sub/run.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+
+        // The output directory is in the input one, and isn't rendered again.
+        render_tree(&["--copy-other"]);
+        assert_eq!(
+            files(),
+            [".tmpl", "app.conf", "plain.txt", "sub/run.sh"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("plain.txt")).unwrap(),
+            "plain $X"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_print_variables() {
+        let template = "$E ${E | upper} ${X:-a} ${Y | default(\"n\\\"o\") | trim}";
+        let variables = ParserBuilder::new()
+            .pipelines(true)
+            .build(template.as_bytes(), sink())
+            .variables()
+            .unwrap();
+        let variables: Vec<_> = variables
+            .into_iter()
+            .map(|variable| (None, variable))
+            .collect();
+
+        let mut output = Vec::new();
+        print_variables(&variables, false, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1:1\tE\n1:4\tE\t| upper\n1:17\tX\t:-a\n1:25\tY\t| default(\"n\\\"o\") | trim\n"
+        );
+
+        let mut output = Vec::new();
+        print_variables_json(&variables[1..2], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[{\"file\":null,\"name\":\"E\",\"operator\":null,\"word\":null,\
+             \"filters\":[{\"name\":\"upper\",\"arguments\":[]}],\"line\":1,\"column\":4,\"offset\":3}]\n"
+        );
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let directory = directory("write");
+        let path = directory.join("output");
+        for text in &["first", "second"] {
+            write_atomically(&path, None, |file| Ok(file.write_all(text.as_bytes())?)).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), *text);
+        }
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = directory("permissions");
+        let path = directory.join("secrets");
+        fs::write(&path, "DB_PASSWORD=$DB_PASSWORD").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let permissions = fs::metadata(&path).unwrap().permissions();
+        write_atomically(&path, Some(permissions), |file| {
+            assert_eq!(file.metadata()?.permissions().mode() & 0o777, 0o600);
+            Ok(file.write_all(b"DB_PASSWORD=hunter2")?)
+        })
+        .unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "DB_PASSWORD=hunter2");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_symlink() {
+        let directory = directory("symlink");
+        let target = directory.join("target");
+        let link = directory.join("link");
+        fs::write(&target, "old").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_atomically(&link, None, |file| Ok(file.write_all(b"new")?)).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_device() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let directory = directory("device");
+        let link = directory.join("null");
+        std::os::unix::fs::symlink("/dev/null", &link).unwrap();
+        for path in &[PathBuf::from("/dev/null"), link] {
+            write_atomically(path, None, |file| Ok(file.write_all(b"text")?)).unwrap();
+            let metadata = fs::symlink_metadata("/dev/null").unwrap();
+            assert!(metadata.file_type().is_char_device());
+        }
+        assert!(fs::symlink_metadata(directory.join("null"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
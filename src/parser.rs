@@ -1,38 +1,35 @@
-use std::char;
-use std::env::{var, VarError};
+use std::collections::HashMap;
 use std::io::{BufRead, BufWriter, Write};
 
-use anyhow::Result;
+use crate::error::{Position, Result};
+use crate::filter::VariableFilter;
+use crate::pipeline::{ValueFilter, ValueFilters};
+use crate::render::{MissingVariablePolicy, Renderer, WarningHandler};
+use crate::source::{Env, VariableSource};
+use crate::syntax::{
+    collect_variables, Escape, Identifiers, Lexer, Markers, Node, Syntax, Variable,
+};
+use crate::template::Template;
 
-const START: char = b'{' as char;
-const END: char = b'}' as char;
-const VALID_CHARS: [char; 1] = [b'_' as char];
-
-#[derive(Debug, PartialEq)]
-enum State {
-    TextOutput,
-    ParsingVariable,
-    OpenBraces,
-}
-
-#[derive(Debug, PartialEq)]
-enum ParseCharResult {
-    Consumed,
-    Ignored,
-}
-
-pub struct Parser<R, W>
+/// Renders a template while it is read, `ParserBuilder` sets its options.
+pub struct Parser<R, W, S = Env>
 where
     R: BufRead,
     W: Write,
+    S: VariableSource,
 {
     input: R,
     output: BufWriter<W>,
-    fail_when_not_found: bool,
-    delimiter: char,
+    source: S,
+    missing: MissingVariablePolicy,
+    filter: VariableFilter,
+    syntax: Syntax,
+    ascii_case: bool,
+    value_filters: ValueFilters,
+    warnings: WarningHandler,
 
-    current_variable_name: String,
-    state: State,
+    /// Variables set with `${VAR:=word}`, they take precedence over `source`.
+    assigned: HashMap<String, Vec<u8>>,
 }
 
 impl<R, W> Parser<R, W>
@@ -40,176 +37,305 @@ where
     R: BufRead,
     W: Write,
 {
-    pub fn new(input: R, output: W, fail_when_not_found: bool, delimiter: Option<char>) -> Self {
-        Self {
-            input,
-            output: BufWriter::new(output),
-            fail_when_not_found,
-            delimiter: delimiter.unwrap_or_else(default_delimiter),
-            current_variable_name: "".to_owned(),
-            state: State::TextOutput,
+    pub fn new(
+        input: R,
+        output: W,
+        missing: MissingVariablePolicy,
+        delimiter: Option<char>,
+    ) -> Self {
+        Self::with_source(input, output, missing, delimiter, Env)
+    }
+}
+
+impl<R, W, S> Parser<R, W, S>
+where
+    R: BufRead,
+    W: Write,
+    S: VariableSource,
+{
+    /// Same as `Parser::new`, but variables are looked up in `source` instead
+    /// of the process environment.
+    pub fn with_source(
+        input: R,
+        output: W,
+        missing: MissingVariablePolicy,
+        delimiter: Option<char>,
+        source: S,
+    ) -> Self {
+        let builder = ParserBuilder::new().source(source).missing(missing);
+        match delimiter {
+            Some(delimiter) => builder.delimiter(delimiter),
+            None => builder,
         }
+        .build(input, output)
     }
 
     pub fn process(&mut self) -> Result<()> {
-        let mut line = String::new();
-        let mut last_processed_line = 0;
-        for line_number in 1.. {
-            if self.input.read_line(&mut line)? == 0 {
-                break;
-            };
-            last_processed_line = line_number;
-
-            for current_char in line.chars() {
-                self.parse_char(current_char)?;
-            }
-            if self.state == State::ParsingVariable {
-                self.write_variable()?;
-            }
-            line.clear();
-        }
+        let output = &mut self.output;
+        let mut renderer = Renderer {
+            source: &self.source,
+            missing: self.missing,
+            filter: &self.filter,
+            assigned: &mut self.assigned,
+            ascii_case: self.ascii_case,
+            value_filters: &self.value_filters,
+            warnings: &self.warnings,
+        };
+        read_pieces(&mut self.input, &self.syntax, |piece| match piece {
+            Piece::Literal(text) => Ok(output.write_all(text)?),
+            Piece::Node(node) => renderer.render_node(&node, output),
+        })?;
 
-        if self.state != State::TextOutput {
-            anyhow::bail!(
-                "Failed to parse a variable on line {} missing a '}}' after '{}'",
-                last_processed_line,
-                self.current_variable_name
-            );
-        }
+        self.output.flush()?;
         Ok(())
     }
 
-    fn parse_char(&mut self, current_char: char) -> Result<()> {
-        if self.start_parsing_variable(current_char)? == ParseCharResult::Consumed {
-            return Ok(());
-        }
-
-        if self.check_braces_opening(current_char)? == ParseCharResult::Consumed {
-            return Ok(());
-        }
+    /// Reads the whole input and returns every variable it references,
+    /// including the ones in the words of operators, without substituting
+    /// anything.
+    pub fn variables(&mut self) -> Result<Vec<Variable>> {
+        let mut variables = Vec::new();
+        read_pieces(&mut self.input, &self.syntax, |piece| {
+            if let Piece::Node(node) = piece {
+                let mut found = Vec::new();
+                collect_variables(std::slice::from_ref(&node), &mut found);
+                variables.extend(found.into_iter().cloned());
+            }
+            Ok(())
+        })?;
+        Ok(variables)
+    }
+}
 
-        if self.check_braces_ending(current_char)? == ParseCharResult::Consumed {
-            return Ok(());
-        }
+/// Options of a `Parser` or a `Template`, set one after the other, e.g.
+/// `ParserBuilder::new().missing(MissingVariablePolicy::Fail).build(input, output)`.
+#[derive(Debug, Clone)]
+pub struct ParserBuilder<S = Env> {
+    source: S,
+    missing: MissingVariablePolicy,
+    filter: VariableFilter,
+    syntax: Syntax,
+    ascii_case: bool,
+    value_filters: ValueFilters,
+    warnings: WarningHandler,
+}
 
-        if self.check_whitespace(current_char)? == ParseCharResult::Consumed {
-            return Ok(());
+impl ParserBuilder {
+    /// Variables are looked up in the process environment, and the ones that
+    /// are not set are replaced with an empty string.
+    pub fn new() -> Self {
+        Self {
+            source: Env,
+            missing: MissingVariablePolicy::default(),
+            filter: VariableFilter::default(),
+            syntax: Syntax::default(),
+            ascii_case: false,
+            value_filters: ValueFilters::new(),
+            warnings: WarningHandler::default(),
         }
+    }
+}
 
-        if self.state == State::ParsingVariable || self.state == State::OpenBraces {
-            if VALID_CHARS.contains(&current_char) || current_char.is_alphabetic() {
-                self.current_variable_name.push(current_char);
-                return Ok(());
-            }
+impl Default for ParserBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            if self.state == State::OpenBraces {
-                anyhow::bail!(
-                    "Failed to parse variable {} with extra character '{}'",
-                    &self.current_variable_name,
-                    current_char
-                );
-            }
-            self.write_variable()?;
-            self.reset_state();
+impl<S> ParserBuilder<S> {
+    /// Looks variables up in `source` instead of the process environment.
+    pub fn source<T: VariableSource>(self, source: T) -> ParserBuilder<T> {
+        ParserBuilder {
+            source,
+            missing: self.missing,
+            filter: self.filter,
+            syntax: self.syntax,
+            ascii_case: self.ascii_case,
+            value_filters: self.value_filters,
+            warnings: self.warnings,
         }
+    }
 
-        self.write_char(current_char)?;
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.syntax.delimiter = delimiter;
+        self
+    }
 
-        Ok(())
+    pub fn missing(mut self, missing: MissingVariablePolicy) -> Self {
+        self.missing = missing;
+        self
     }
 
-    fn start_parsing_variable(&mut self, current_char: char) -> Result<ParseCharResult> {
-        if current_char == self.delimiter {
-            if self.state == State::ParsingVariable {
-                anyhow::bail!("Variable is already being parsed")
-            }
-            self.state = State::ParsingVariable;
-            return Ok(ParseCharResult::Consumed);
-        }
+    /// Calls `handler` with every variable that is not set when the policy is
+    /// `MissingVariablePolicy::Warn`, they are silently replaced otherwise.
+    pub fn on_warning<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Variable) + Send + Sync + 'static,
+    {
+        self.warnings = WarningHandler::new(handler);
+        self
+    }
 
-        Ok(ParseCharResult::Ignored)
+    /// Sets how a literal delimiter can be written, by default there is no way
+    /// to do so.
+    pub fn escape(mut self, escape: Escape) -> Self {
+        self.syntax.escape = escape;
+        self
     }
 
-    fn check_braces_opening(&mut self, current_char: char) -> Result<ParseCharResult> {
-        if current_char != START {
-            return Ok(ParseCharResult::Ignored);
-        }
+    /// Restricts which variables are substituted, references to the others are
+    /// kept as they are.
+    pub fn filter(mut self, filter: VariableFilter) -> Self {
+        self.filter = filter;
+        self
+    }
 
-        if self.state == State::ParsingVariable {
-            self.state = State::OpenBraces;
-            return Ok(ParseCharResult::Consumed);
-        }
+    /// Sets which characters variable names are made of.
+    pub fn identifiers(mut self, identifiers: Identifiers) -> Self {
+        self.syntax.identifiers = identifiers;
+        self
+    }
 
-        if self.state == State::OpenBraces {
-            anyhow::bail!("Double open braces")
-        }
+    /// Fails on input that is not valid UTF-8, by default it is written as it
+    /// is.
+    pub fn strict_utf8(mut self, strict: bool) -> Self {
+        self.syntax.strict_utf8 = strict;
+        self
+    }
 
-        Ok(ParseCharResult::Ignored)
+    /// Uses custom markers around variables instead of the delimiter and
+    /// braces, e.g. `Markers::new("{{", "}}")` for `{{NAME}}`.
+    pub fn markers(mut self, markers: Markers) -> Self {
+        self.syntax.markers = Some(markers);
+        self
     }
 
-    fn check_braces_ending(&mut self, current_char: char) -> Result<ParseCharResult> {
-        if current_char != END {
-            return Ok(ParseCharResult::Ignored);
-        }
+    /// Only substitutes `${NAME}`, so a bare `$NAME` is written as it is.
+    pub fn braced_only(mut self, braced_only: bool) -> Self {
+        self.syntax.braced_only = braced_only;
+        self
+    }
 
-        if self.state == State::OpenBraces {
-            self.write_variable()?;
-            return Ok(ParseCharResult::Consumed);
-        }
+    /// Only changes the case of ASCII letters with `${VAR^^}` and the other
+    /// case operators, by default every Unicode letter is converted.
+    pub fn ascii_case(mut self, ascii: bool) -> Self {
+        self.ascii_case = ascii;
+        self
+    }
 
-        if self.state == State::ParsingVariable {
-            anyhow::bail!("Closing braces without opening");
-        }
+    /// Parses `${NAME | filter | filter("argument")}` pipelines, see
+    /// `ValueFilters` for the filters available by default.
+    pub fn pipelines(mut self, pipelines: bool) -> Self {
+        self.syntax.pipelines = pipelines;
+        self
+    }
 
-        Ok(ParseCharResult::Ignored)
+    /// Adds a filter for pipelines, or replaces the one with the same name.
+    pub fn value_filter<F>(mut self, name: &str, filter: F) -> Self
+    where
+        F: ValueFilter + Send + Sync + 'static,
+    {
+        self.value_filters.insert(name, filter);
+        self
     }
 
-    fn check_whitespace(&mut self, current_char: char) -> Result<ParseCharResult> {
-        if self.state != State::ParsingVariable && self.state != State::OpenBraces {
-            return Ok(ParseCharResult::Ignored);
-        }
-        if current_char.is_ascii_whitespace() {
-            if self.state == State::OpenBraces {
-                anyhow::bail!("Braces not closed");
-            }
-            self.write_variable()?;
-            self.write_char(current_char)?;
-            return Ok(ParseCharResult::Consumed);
+    /// Parses `text` at once with these options, except for the source which
+    /// is given to `Template::render`.
+    pub fn template(&self, text: &str) -> Result<Template> {
+        Template::parse_with(
+            text,
+            &self.syntax,
+            self.missing,
+            self.filter.clone(),
+            self.ascii_case,
+            self.value_filters.clone(),
+            self.warnings.clone(),
+        )
+    }
+}
+
+impl<S: VariableSource> ParserBuilder<S> {
+    /// A parser that streams `input` to `output`.
+    pub fn build<R: BufRead, W: Write>(self, input: R, output: W) -> Parser<R, W, S> {
+        Parser {
+            input,
+            output: BufWriter::new(output),
+            source: self.source,
+            missing: self.missing,
+            filter: self.filter,
+            syntax: self.syntax,
+            ascii_case: self.ascii_case,
+            value_filters: self.value_filters,
+            warnings: self.warnings,
+            assigned: HashMap::new(),
         }
-        Ok(ParseCharResult::Ignored)
     }
+}
 
-    fn write_variable(&mut self) -> Result<()> {
-        let result = match var(&self.current_variable_name) {
-            Ok(result) => result,
-            Err(VarError::NotPresent) => {
-                if self.fail_when_not_found {
-                    anyhow::bail!("The variable {} is not set", self.current_variable_name)
-                }
-                "".to_owned()
-            }
-            Err(error) => {
-                return Err(anyhow::Error::new(error).context(format!(
-                    "failed to read contents of variable {}",
-                    &self.current_variable_name
-                )));
-            }
-        };
+/// What `read_pieces` finds in the input.
+enum Piece<'a> {
+    /// Text that is written as it is, borrowed from the input buffer.
+    Literal(&'a [u8]),
+    Node(Node),
+}
 
-        self.output.write_all(result.as_bytes())?;
-        self.reset_state();
-        Ok(())
-    }
+/// Splits `input` into pieces and passes them to `handle` one at a time.
+///
+/// The lexer works on the buffer of `input` directly, so memory use doesn't
+/// depend on the length of lines. Only a node that is cut at the end of the
+/// buffer is copied, until the rest of it is read.
+fn read_pieces<R, F>(input: &mut R, syntax: &Syntax, mut handle: F) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(Piece<'_>) -> Result<()>,
+{
+    let mut pending = Vec::new();
+    // A node running past the buffer is lexed again from its start, so that
+    // is only done once it has doubled in size to keep long nodes linear.
+    let mut retry_at = 0;
+    let mut position = Position::default();
+    loop {
+        let buffer = input.fill_buf()?;
+        let complete = buffer.is_empty();
+        let length = buffer.len();
+        if pending.is_empty() {
+            let mut lexer = Lexer::new(buffer, syntax, complete, position);
+            lex(&mut lexer, &mut handle)?;
+            position = lexer.position();
+            pending.extend_from_slice(&buffer[lexer.consumed()..]);
+            retry_at = pending.len() * 2;
+        } else {
+            pending.extend_from_slice(buffer);
+            if complete || pending.len() >= retry_at {
+                let mut lexer = Lexer::new(&pending, syntax, complete, position);
+                lex(&mut lexer, &mut handle)?;
+                position = lexer.position();
+                let consumed = lexer.consumed();
+                pending.drain(..consumed);
+                retry_at = pending.len() * 2;
+            }
+        }
+        input.consume(length);
 
-    fn reset_state(&mut self) {
-        self.state = State::TextOutput;
-        self.current_variable_name.clear();
+        if complete {
+            return Ok(());
+        }
     }
+}
 
-    fn write_char(&mut self, current_char: char) -> Result<()> {
-        // TODO: No way to access bytes from char?
-        self.output.write_all(current_char.to_string().as_bytes())?;
-        Ok(())
+fn lex<F>(lexer: &mut Lexer<'_>, handle: &mut F) -> Result<()>
+where
+    F: FnMut(Piece<'_>) -> Result<()>,
+{
+    loop {
+        let literal = lexer.literal();
+        if !literal.is_empty() {
+            handle(Piece::Literal(literal))?;
+        }
+        match lexer.next_node()? {
+            Some(node) => handle(Piece::Node(node))?,
+            None => return Ok(()),
+        }
     }
 }
 
@@ -219,69 +345,108 @@ pub fn default_delimiter() -> char {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::{BTreeMap, HashMap};
     use std::env::set_var;
     use std::io::{BufReader, Cursor};
+    use std::sync::{Arc, Mutex};
 
-    use crate::parser::Parser;
-    use std::panic;
+    use crate::error::{Error, Position};
+    use crate::filter::VariableFilter;
+    use crate::parser::{Parser, ParserBuilder};
+    use crate::render::MissingVariablePolicy;
+    use crate::source::{SourceError, VariableSource};
+    use crate::syntax::{Escape, Identifiers, Markers, Variable};
 
-    fn render(template: &str, expected: &str, fail_when_not_found: bool, delimiter: Option<char>) {
-        let mut input = BufReader::new(Cursor::new(template));
-        let mut output = Cursor::new(Vec::new());
-        {
-            let mut parser = Parser::new(&mut input, &mut output, fail_when_not_found, delimiter);
-            parser.process().unwrap();
-        }
-        let output = String::from_utf8(output.into_inner()).unwrap();
-        assert_eq!(output, expected);
+    /// Renders `template` with a parser built by `builder`, which reads the
+    /// input `capacity` bytes at a time.
+    fn render_chunked<S: VariableSource>(
+        builder: ParserBuilder<S>,
+        template: &[u8],
+        capacity: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let input = BufReader::with_capacity(capacity, template);
+        let mut output = Vec::new();
+        builder.build(input, &mut output).process()?;
+        Ok(output)
+    }
+
+    fn render<S: VariableSource>(
+        builder: ParserBuilder<S>,
+        template: &str,
+    ) -> Result<String, Error> {
+        let output = render_chunked(builder, template.as_bytes(), 8 * 1024)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    /// Looks variables up in the process environment and fails on the ones
+    /// that are not set.
+    fn env() -> ParserBuilder {
+        ParserBuilder::new().missing(MissingVariablePolicy::Fail)
+    }
+
+    fn source(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Looks variables up in `variables` and fails on the ones that are not
+    /// set.
+    fn with_variables(variables: &[(&str, &str)]) -> ParserBuilder<HashMap<String, String>> {
+        env().source(source(variables))
     }
 
     #[test]
     fn test_simple_variable() {
         set_var("TEST_SIMPLE", "simple return");
-        render("$TEST_SIMPLE", "simple return", true, None);
+        assert_eq!(render(env(), "$TEST_SIMPLE").unwrap(), "simple return");
     }
 
     #[test]
     fn test_simple_variable_with_delimiter() {
         set_var("TEST_SIMPLE", "simple return");
-        render("👻TEST_SIMPLE", "simple return", true, Some('👻'));
+        assert_eq!(
+            render(env().delimiter('👻'), "👻TEST_SIMPLE").unwrap(),
+            "simple return"
+        );
     }
 
     #[test]
     fn test_simple_quoted_variable() {
         set_var("TEST_SIMPLE", "simple return");
-        render("'$TEST_SIMPLE'", "'simple return'", true, None);
+        assert_eq!(render(env(), "'$TEST_SIMPLE'").unwrap(), "'simple return'");
     }
 
     #[test]
     fn test_with_braces() {
         set_var("TEST_BRACES", "braces return");
-        render("${TEST_BRACES}", "braces return", true, None);
+        assert_eq!(render(env(), "${TEST_BRACES}").unwrap(), "braces return");
     }
 
     #[test]
     fn test_with_quoted_braces() {
         set_var("TEST_BRACES", "braces return");
-        render("'${TEST_BRACES}'", "'braces return'", true, None);
+        assert_eq!(
+            render(env(), "'${TEST_BRACES}'").unwrap(),
+            "'braces return'"
+        );
     }
 
     #[test]
     fn test_mixed() {
         set_var("TEST_SIMPLE", "simple return");
         set_var("TEST_BRACES", "braces return");
-        render(
-            "simple: $TEST_SIMPLE\nbraces: ${TEST_BRACES}",
-            "simple: simple return\nbraces: braces return",
-            true,
-            None,
+        assert_eq!(
+            render(env(), "simple: $TEST_SIMPLE\nbraces: ${TEST_BRACES}").unwrap(),
+            "simple: simple return\nbraces: braces return"
         );
     }
 
     #[test]
     fn test_missing() {
         for template in &["$TEST_MISSING", "${TEST_MISSING}"] {
-            render(template, "", false, None);
+            assert_eq!(render(ParserBuilder::new(), template).unwrap(), "");
         }
     }
 
@@ -290,13 +455,889 @@ mod tests {
         let mut input = BufReader::new(Cursor::new("${OPEN_BRACES"));
         let mut output = Cursor::new(Vec::new());
 
-        let mut parser = Parser::new(&mut input, &mut output, true, None);
+        let mut parser = Parser::new(&mut input, &mut output, MissingVariablePolicy::Fail, None);
         let result = parser.process();
         assert!(result.is_err());
         let error = result.unwrap_err();
         assert_eq!(
             error.to_string(),
-            "Failed to parse a variable on line 1 missing a '}' after 'OPEN_BRACES'"
+            "Failed to parse a variable on line 1, column 1 missing a '}' after 'OPEN_BRACES'"
+        );
+    }
+
+    #[test]
+    fn test_hash_map_source() {
+        let mut source = HashMap::new();
+        source.insert("FROM_MAP".to_owned(), "map return".to_owned());
+        assert_eq!(
+            render(env().source(source), "${FROM_MAP} $FROM_MAP").unwrap(),
+            "map return map return"
+        );
+    }
+
+    #[test]
+    fn test_btree_map_source() {
+        let mut source = BTreeMap::new();
+        source.insert("FROM_MAP".to_owned(), "map return".to_owned());
+        assert_eq!(
+            render(env().source(source), "${FROM_MAP}").unwrap(),
+            "map return"
+        );
+    }
+
+    #[test]
+    fn test_closure_source() {
+        let source = |name: &str| Some(format!("{} from closure", name));
+        assert_eq!(
+            render(env().source(source), "$NAME").unwrap(),
+            "NAME from closure"
+        );
+    }
+
+    #[test]
+    fn test_source_missing_variable() {
+        assert_eq!(
+            render(with_variables(&[]), "$NOT_IN_MAP")
+                .unwrap_err()
+                .to_string(),
+            "The variable NOT_IN_MAP is not set on line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn test_use_default() {
+        let builder = with_variables(&[("SET", "value"), ("EMPTY", "")]);
+        let render = |template| render(builder.clone(), template).unwrap();
+        assert_eq!(render("${SET:-default}"), "value");
+        assert_eq!(render("${SET-default}"), "value");
+        assert_eq!(render("${UNSET:-default}"), "default");
+        assert_eq!(render("${UNSET-default}"), "default");
+        assert_eq!(render("${EMPTY:-default}"), "default");
+        assert_eq!(render("${EMPTY-default}"), "");
+    }
+
+    #[test]
+    fn test_default_with_whitespace() {
+        let render = |template| render(with_variables(&[]), template).unwrap();
+        assert_eq!(render("${UNSET:-hello world}!"), "hello world!");
+        assert_eq!(render("${UNSET:-}"), "");
+    }
+
+    #[test]
+    fn test_nested_default() {
+        let builder = with_variables(&[("PORT", "8080"), ("HOST", "localhost")]);
+        let render = |template| render(builder.clone(), template).unwrap();
+        assert_eq!(
+            render("${URL:-http://${HOST}:$PORT/}"),
+            "http://localhost:8080/"
+        );
+        assert_eq!(render("${UNSET:-${ALSO_UNSET:-$PORT}}"), "8080");
+    }
+
+    #[test]
+    fn test_assign_default() {
+        let builder = with_variables(&[("EMPTY", "")]);
+        let render = |template| render(builder.clone(), template).unwrap();
+        assert_eq!(
+            render("${NAME:=first} ${NAME:=second} $NAME"),
+            "first first first"
+        );
+        assert_eq!(render("${EMPTY=unused}[$EMPTY]"), "[]");
+        assert_eq!(render("${EMPTY:=used}[$EMPTY]"), "used[used]");
+    }
+
+    #[test]
+    fn test_string_operators() {
+        let builder = with_variables(&[
+            ("FILE", "/srv/app/config.tar.gz"),
+            ("PATH", "/usr/bin:/bin:/usr/local/bin"),
+            ("NAME", "wörld"),
+            ("EXT", ".gz"),
+            ("SIX", "6"),
+        ])
+        .missing(MissingVariablePolicy::Empty);
+        let cases = [
+            ("${#FILE} ${#NAME}", "22 5"),
+            ("${FILE:5} ${FILE:5:3} ${FILE: -6} ${FILE:(-6):3}", "app/config.tar.gz app tar.gz tar"),
+            ("${FILE:5:-7} ${FILE::4} ${NAME:1:2} [${FILE:100}] ${FILE:-$SIX}", "app/config /srv ör [] /srv/app/config.tar.gz"),
+            ("${FILE:$SIX:1}", "p"),
+            ("${FILE#*/} ${FILE##*/} ${FILE#[a-z]}", "srv/app/config.tar.gz config.tar.gz /srv/app/config.tar.gz"),
+            ("${FILE%.*} ${FILE%%.*} ${FILE%$EXT}", "/srv/app/config.tar /srv/app/config /srv/app/config.tar"),
+            ("${PATH/bin/sbin} ${PATH//bin/sbin}", "/usr/sbin:/bin:/usr/local/bin /usr/sbin:/sbin:/usr/local/sbin"),
+            ("${PATH//\\//|} ${PATH//:} ${FILE/*./X} ${PATH//b?n/B} ${NAME//ö/o}", "|usr|bin:|bin:|usr|local|bin /usr/bin/bin/usr/local/bin Xgz /usr/B:/B:/usr/local/B world"),
+            ("${#UNSET}${UNSET%x}", "0"),
+        ];
+        for (template, expected) in &cases {
+            assert_eq!(
+                &render(builder.clone(), template).unwrap(),
+                expected,
+                "{}",
+                template
+            );
+        }
+
+        assert_eq!(
+            render(builder.clone(), "${FILE:x}")
+                .unwrap_err()
+                .to_string(),
+            "Invalid number 'x' for variable FILE on line 1, column 1"
+        );
+
+        let render_missing = |missing| {
+            render(
+                with_variables(&[]).missing(missing),
+                "${#UNSET} ${UNSET//a/b}",
+            )
+            .map_err(|error| error.to_string())
+        };
+        assert_eq!(
+            render_missing(MissingVariablePolicy::Keep),
+            Ok("${#UNSET} ${UNSET//a/b}".to_owned())
+        );
+        assert_eq!(
+            render_missing(MissingVariablePolicy::Fail),
+            Err("The variable UNSET is not set on line 1, column 1".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_case_operators() {
+        let builder = with_variables(&[("A", "HELLO_World"), ("B", "élan"), ("C", "straße")])
+            .missing(MissingVariablePolicy::Empty);
+        let render_case =
+            |template, ascii| render(builder.clone().ascii_case(ascii), template).unwrap();
+        assert_eq!(
+            render_case(
+                "${A,,} ${A^^} ${A,} ${A,,[A-L]} ${A^^[o]} ${A,[A-G]}",
+                false
+            ),
+            "hello_world HELLO_WORLD hELLO_World hellO_World HELLO_WOrld HELLO_World"
+        );
+        assert_eq!(
+            render_case("${B^} ${B^^} ${C^^} ${B^[a-z]} ${UNSET^^}", false),
+            "Élan ÉLAN STRASSE élan "
+        );
+        assert_eq!(
+            render_case("${B^} ${B^^} ${C^^} ${A,,}", true),
+            "élan éLAN STRAßE hello_world"
+        );
+    }
+
+    #[test]
+    fn test_pipelines() {
+        let builder = with_variables(&[("NAME", " Wörld "), ("EMPTY", "")])
+            .pipelines(true)
+            .value_filter(
+                "wrap",
+                |value: &str, arguments: &[String]| -> Result<String, SourceError> {
+                    Ok(format!("{}{}{}", arguments[0], value, arguments[1]))
+                },
+            );
+        let render_ok = |template| render(builder.clone(), template).unwrap();
+        assert_eq!(render_ok("${NAME | trim | upper}"), "WÖRLD");
+        #[cfg(feature = "base64")]
+        assert_eq!(render_ok("${NAME|trim|lower|base64}"), "d8O2cmxk");
+        #[cfg(feature = "sha2")]
+        assert_eq!(render_ok("${NAME | trim | sha256}").len(), 64);
+        assert_eq!(
+            render_ok("${NAME | shell} ${NAME | url-encode}"),
+            "' Wörld ' %20W%C3%B6rld%20"
+        );
+        assert_eq!(
+            render_ok("${UNSET | default(\"a \\\"b\\\" | }\") | json}"),
+            "a \\\"b\\\" | }"
+        );
+        assert_eq!(render_ok("${EMPTY | default('x') | upper}"), "X");
+        assert_eq!(
+            render_ok("${NAME | trim | wrap(<, \">\" )} ${NAME|wrap(\"\",'')}"),
+            "<Wörld>  Wörld "
+        );
+        assert_eq!(render_ok("a|b $NAME| ${NAME:-x|y}"), "a|b  Wörld |  Wörld ");
+
+        let render_error = |template| render(builder.clone(), template).unwrap_err().to_string();
+        assert_eq!(
+            render_error("${UNSET | upper}"),
+            "The variable UNSET is not set on line 1, column 1"
+        );
+        assert_eq!(
+            render_error("${NAME | nope}"),
+            "Unknown filter 'nope' for variable NAME on line 1, column 1"
+        );
+        assert_eq!(
+            render_error("${NAME | default}"),
+            "Filter 'default' failed for variable NAME on line 1, column 1"
+        );
+        assert_eq!(
+            render_error("${NAME | upper x}"),
+            "Failed to parse variable NAME with extra character 'x' on line 1, column 1"
+        );
+        assert_eq!(
+            render_error("${NAME | default(\"x\""),
+            "Failed to parse a variable on line 1, column 1 missing a '}' after 'NAME'"
+        );
+        assert_eq!(
+            render(
+                builder.clone().missing(MissingVariablePolicy::Keep),
+                "${UNSET | upper}"
+            )
+            .unwrap(),
+            "${UNSET | upper}"
+        );
+
+        let error = render(ParserBuilder::new(), "${NAME | upper}").unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidCharacter { character: ' ', .. }
+        ));
+    }
+
+    #[test]
+    fn test_pipelines_with_markers() {
+        let builder = with_variables(&[("NAME", "wörld")])
+            .missing(MissingVariablePolicy::Empty)
+            .markers(Markers::new("{{", "}}").whitespace(true))
+            .pipelines(true);
+        assert_eq!(
+            render(builder, "{{ NAME | upper }} {{NAME|json}} a | b {{ x }}").unwrap(),
+            "WÖRLD wörld a | b "
+        );
+    }
+
+    #[test]
+    fn test_unsupported_operator() {
+        let mut input = BufReader::new(Cursor::new("${NAME~x}"));
+        let mut output = Cursor::new(Vec::new());
+
+        let mut parser = Parser::new(&mut input, &mut output, MissingVariablePolicy::Fail, None);
+        let error = parser.process().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidCharacter { character: '~', .. }
+        ));
+    }
+
+    #[test]
+    fn test_required() {
+        let builder = with_variables(&[("SET", "value"), ("EMPTY", "")])
+            .missing(MissingVariablePolicy::Empty);
+        let render =
+            |template| render(builder.clone(), template).map_err(|error| error.to_string());
+        assert_eq!(render("${SET:?must be set}").unwrap(), "value");
+        assert_eq!(render("${SET?must be set}").unwrap(), "value");
+        assert_eq!(render("[${EMPTY?must be set}]").unwrap(), "[]");
+        assert_eq!(
+            render("${EMPTY:?must be set}").unwrap_err(),
+            "EMPTY: must be set on line 1, column 1"
+        );
+        assert_eq!(
+            render("optional: $OPTIONAL\n  ${UNSET?must be set}").unwrap_err(),
+            "UNSET: must be set on line 2, column 3"
+        );
+    }
+
+    #[test]
+    fn test_required_default_message() {
+        let render_error = |template| {
+            render(with_variables(&[]), template)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            render_error("${UNSET:?}"),
+            "UNSET: parameter null or not set on line 1, column 1"
+        );
+        assert_eq!(
+            render_error("${UNSET?}"),
+            "UNSET: parameter not set on line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn test_required_message_is_expanded() {
+        let builder = with_variables(&[("ENVIRONMENT", "production")]);
+        assert_eq!(
+            render(builder, "${DB_PASSWORD:?missing in $ENVIRONMENT}")
+                .unwrap_err()
+                .to_string(),
+            "DB_PASSWORD: missing in production on line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn test_required_nested() {
+        assert_eq!(
+            render(with_variables(&[]), "${UNSET:-\n  ${NESTED:?is required}}")
+                .unwrap_err()
+                .to_string(),
+            "NESTED: is required on line 2, column 3"
+        );
+    }
+
+    #[test]
+    fn test_alternate() {
+        let builder = with_variables(&[("DEBUG", "1"), ("EMPTY", "")]);
+        let render = |template| render(builder.clone(), template).unwrap();
+        assert_eq!(render("run ${DEBUG:+--verbose}"), "run --verbose");
+        assert_eq!(render("run ${DEBUG+--verbose}"), "run --verbose");
+        assert_eq!(render("run ${EMPTY:+--verbose}"), "run ");
+        assert_eq!(render("run ${EMPTY+--verbose}"), "run --verbose");
+        assert_eq!(render("run ${UNSET:+--verbose}"), "run ");
+        assert_eq!(render("run ${UNSET+--verbose}"), "run ");
+    }
+
+    #[test]
+    fn test_nested_alternate() {
+        let builder = with_variables(&[("DEBUG", "1"), ("LEVEL", "trace")]);
+        let render = |template| render(builder.clone(), template).unwrap();
+        assert_eq!(
+            render("${DEBUG:+--log-level=${LEVEL:-debug}}"),
+            "--log-level=trace"
+        );
+        assert_eq!(render("${UNSET:+${MISSING:?not expanded}}"), "");
+    }
+
+    #[test]
+    fn test_double_escape() {
+        let builder = with_variables(&[("HOST", "example.com")]).escape(Escape::Double);
+        assert_eq!(
+            render(
+                builder.clone(),
+                "server_name $HOST; proxy_set_header Host $$host;"
+            )
+            .unwrap(),
+            "server_name example.com; proxy_set_header Host $host;"
+        );
+        assert_eq!(
+            render(builder.clone(), "$${HOST} $$1 $$").unwrap(),
+            "${HOST} $1 $"
+        );
+        assert_eq!(render(builder, "\\$HOST").unwrap(), "\\example.com");
+    }
+
+    #[test]
+    fn test_backslash_escape() {
+        let builder = with_variables(&[("HOST", "example.com")]).escape(Escape::Backslash);
+        assert_eq!(
+            render(builder.clone(), "echo \\$1 $HOST \\${HOST}").unwrap(),
+            "echo $1 example.com ${HOST}"
+        );
+        assert_eq!(
+            render(builder, "C:\\path\\file $HOST\\").unwrap(),
+            "C:\\path\\file example.com\\"
+        );
+    }
+
+    #[test]
+    fn test_both_escapes() {
+        let builder = with_variables(&[("HOST", "example.com")]).escape(Escape::Both);
+        assert_eq!(
+            render(builder, "$$1 \\$2 $HOST").unwrap(),
+            "$1 $2 example.com"
+        );
+    }
+
+    #[test]
+    fn test_escape_in_word() {
+        let builder = with_variables(&[]);
+        assert_eq!(
+            render(builder.clone().escape(Escape::Double), "${UNSET:-$$HOME}").unwrap(),
+            "$HOME"
+        );
+        assert_eq!(
+            render(builder.escape(Escape::Backslash), "${UNSET:-\\$HOME}").unwrap(),
+            "$HOME"
+        );
+    }
+
+    #[test]
+    fn test_no_escape() {
+        let builder = with_variables(&[("HOST", "example.com")]).escape(Escape::None);
+        assert_eq!(
+            render(builder, "$$HOST \\$HOST").unwrap(),
+            "$example.com \\example.com"
+        );
+    }
+
+    #[test]
+    fn test_adjacent_variables() {
+        let builder = with_variables(&[("HOST", "example.com")]).escape(Escape::None);
+        assert_eq!(
+            render(builder, "$HOST$HOST ${HOST}$HOST {\"host\": $HOST}").unwrap(),
+            "example.comexample.com example.comexample.com {\"host\": example.com}"
+        );
+    }
+
+    #[test]
+    fn test_allowed_variables() {
+        let builder = with_variables(&[("HOST", "example.com"), ("APP_PORT", "8080")])
+            .filter(VariableFilter::new().allow("HOST").allow("APP_*"));
+        assert_eq!(
+            render(
+                builder,
+                "server_name $HOST:${APP_PORT}; set $remote $remote_addr;"
+            )
+            .unwrap(),
+            "server_name example.com:8080; set $remote $remote_addr;"
+        );
+    }
+
+    #[test]
+    fn test_denied_variables_are_kept() {
+        let builder = with_variables(&[("HOST", "example.com"), ("APP_PORT", "8080")])
+            .filter(VariableFilter::new().deny("HOST"));
+        assert_eq!(
+            render(
+                builder,
+                "$HOST ${HOST} ${HOST:-default} ${HOST:+x} $APP_PORT"
+            )
+            .unwrap(),
+            "$HOST ${HOST} ${HOST:-default} ${HOST:+x} 8080"
+        );
+    }
+
+    #[test]
+    fn test_filter_in_word() {
+        let builder = with_variables(&[("HOST", "example.com"), ("APP_PORT", "8080")])
+            .filter(VariableFilter::from_shell_format("$URL $APP_PORT", '$'));
+        assert_eq!(
+            render(builder, "${URL:-http://$HOST:$APP_PORT}").unwrap(),
+            "http://$HOST:8080"
+        );
+    }
+
+    #[test]
+    fn test_keep_missing() {
+        let builder =
+            with_variables(&[("HOST", "example.com")]).missing(MissingVariablePolicy::Keep);
+        assert_eq!(
+            render(
+                builder,
+                "$HOST $UNKNOWN ${UNKNOWN}, ${UNKNOWN:-$HOST} ${UNSET:-${UNKNOWN}}"
+            )
+            .unwrap(),
+            "example.com $UNKNOWN ${UNKNOWN}, example.com ${UNKNOWN}"
+        );
+    }
+
+    #[test]
+    fn test_warn_missing() {
+        let warned = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let warned = Arc::clone(&warned);
+            move |variable: &Variable| {
+                let warning = format!("{} on {}", variable.name, variable.span.start);
+                warned.lock().unwrap().push(warning);
+            }
+        };
+        let builder = with_variables(&[("HOST", "example.com")])
+            .missing(MissingVariablePolicy::Warn)
+            .on_warning(handler);
+        assert_eq!(
+            render(builder.clone(), "$HOST [$UNKNOWN]\n${OTHER}").unwrap(),
+            "example.com []\n"
+        );
+        assert_eq!(
+            *warned.lock().unwrap(),
+            vec!["UNKNOWN on line 1, column 8", "OTHER on line 2, column 1"]
+        );
+
+        let without_handler = with_variables(&[]).missing(MissingVariablePolicy::Warn);
+        assert_eq!(render(without_handler, "[$UNKNOWN]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!("keep".parse(), Ok(MissingVariablePolicy::Keep));
+        assert_eq!("warn".parse(), Ok(MissingVariablePolicy::Warn));
+        assert!("ignore".parse::<MissingVariablePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_digits_in_names() {
+        let builder = with_variables(&[
+            ("AWS_REGION_2", "eu-west-1"),
+            ("S3_BUCKET_V2", "bucket"),
+            ("HTTP2_ENABLED", "on"),
+        ]);
+        assert_eq!(
+            render(builder, "$AWS_REGION_2 ${S3_BUCKET_V2} $HTTP2_ENABLED").unwrap(),
+            "eu-west-1 bucket on"
+        );
+    }
+
+    #[test]
+    fn test_lone_delimiter() {
+        assert_eq!(
+            render(with_variables(&[]), "echo $1 costs 5$ or $.50 {$}\n$").unwrap(),
+            "echo $1 costs 5$ or $.50 {$}\n$"
+        );
+    }
+
+    #[test]
+    fn test_unicode_identifiers() {
+        let builder = with_variables(&[("CAF", "caf"), ("CAFÉ", "café")]);
+        assert_eq!(
+            render(builder.clone().identifiers(Identifiers::Unicode), "$CAFÉ").unwrap(),
+            "café"
+        );
+        assert_eq!(
+            render(builder.clone().identifiers(Identifiers::Ascii), "$CAFÉ").unwrap(),
+            "cafÉ"
+        );
+        assert_eq!(render(builder, "$CAFÉ ${CAF}É").unwrap(), "cafÉ cafÉ");
+    }
+
+    #[test]
+    fn test_braced_only() {
+        let builder = with_variables(&[("HOST", "example.com")])
+            .escape(Escape::Double)
+            .braced_only(true);
+        assert_eq!(
+            render(
+                builder,
+                "for f in $files; do echo ${HOST} $f; done ${UNSET:-$HOST} $${HOST} $"
+            )
+            .unwrap(),
+            "for f in $files; do echo example.com $f; done $HOST ${HOST} $"
+        );
+    }
+
+    #[test]
+    fn test_dots_in_braces() {
+        let builder = with_variables(&[("CAF", "caf"), ("database.host", "db.local")]);
+        for identifiers in &[Identifiers::Unicode, Identifiers::Ascii] {
+            assert_eq!(
+                render(
+                    builder.clone().identifiers(*identifiers),
+                    "${database.host} $CAF. ${database.host:-x} ${CAF-x}"
+                )
+                .unwrap(),
+                "db.local caf. db.local caf"
+            );
+        }
+    }
+
+    #[test]
+    fn test_extended_identifiers() {
+        let builder =
+            with_variables(&[("database.host", "db.local")]).identifiers(Identifiers::Extended);
+        assert_eq!(
+            render(builder.clone(), "${database.host}").unwrap(),
+            "db.local"
+        );
+        assert_eq!(render(builder, "$database.host").unwrap(), "db.local");
+    }
+
+    #[test]
+    fn test_leading_digit_in_braces() {
+        let mut input = BufReader::new(Cursor::new("${2FA}"));
+        let mut output = Cursor::new(Vec::new());
+
+        let mut parser = Parser::new(&mut input, &mut output, MissingVariablePolicy::Fail, None);
+        let error = parser.process().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse variable  with extra character '2' on line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let parse_error = |template| render(with_variables(&[]), template).unwrap_err();
+        let position = Position {
+            line: 2,
+            column: 5,
+            offset: 11,
+        };
+        match parse_error("héllo\nabc ${UNCLOSED") {
+            Error::UnclosedBrace { name, position: at } => {
+                assert_eq!(name, "UNCLOSED");
+                assert_eq!(at, position);
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+        match parse_error("héllo\nabc ${WITH SPACE}") {
+            Error::InvalidCharacter {
+                character: ' ',
+                position: at,
+                ..
+            } => assert_eq!(at, position),
+            error => panic!("unexpected error {:?}", error),
+        }
+        match parse_error("héllo\nabc $MISSING") {
+            Error::MissingVariable { name, position: at } => {
+                assert_eq!(name, "MISSING");
+                assert_eq!(at, position);
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+        assert_eq!(
+            parse_error("${UNSET:-\n  ${NESTED:?required}}").position(),
+            Some(Position {
+                line: 2,
+                column: 3,
+                offset: 12,
+            })
+        );
+    }
+
+    #[test]
+    fn test_source_error() {
+        struct Failing;
+
+        impl VariableSource for Failing {
+            fn lookup(&self, _: &str) -> Result<Option<String>, SourceError> {
+                Err("secret store unreachable".into())
+            }
+        }
+
+        let mut input = BufReader::new(Cursor::new("$SECRET"));
+        let mut output = Cursor::new(Vec::new());
+        let mut parser = Parser::with_source(
+            &mut input,
+            &mut output,
+            MissingVariablePolicy::Fail,
+            None,
+            Failing,
+        );
+        let error = parser.process().unwrap_err();
+        assert!(matches!(error, Error::Source { .. }));
+        assert_eq!(
+            std::error::Error::source(&error).unwrap().to_string(),
+            "secret store unreachable"
+        );
+    }
+
+    #[test]
+    fn test_list_variables() {
+        let mut input = BufReader::new(Cursor::new("$HOST\n${PORT:-$DEFAULT_PORT} $$1"));
+        let mut output = Cursor::new(Vec::new());
+        let variables = env()
+            .escape(Escape::Double)
+            .build(&mut input, &mut output)
+            .variables()
+            .unwrap();
+        let names: Vec<&str> = variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["HOST", "PORT", "DEFAULT_PORT"]);
+        assert_eq!(variables[1].span.start.line, 2);
+        assert!(output.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_non_utf8_passthrough() {
+        let builder = with_variables(&[("NAME", "wörld")]);
+        let template = b"caf\xe9 $NAME\n\xff\xfe${NAME:-d\xe9faut}\x00";
+        assert_eq!(
+            render_chunked(builder.clone(), template, 8 * 1024).unwrap(),
+            b"caf\xe9 w\xc3\xb6rld\n\xff\xfew\xc3\xb6rld\x00".to_vec()
+        );
+        assert_eq!(
+            render_chunked(builder.clone(), b"${MISSING:-d\xe9faut}", 8 * 1024).unwrap(),
+            b"d\xe9faut".to_vec()
+        );
+        assert_eq!(
+            render(builder.strict_utf8(true), "ünïcödé $NAME").unwrap(),
+            "ünïcödé wörld"
+        );
+    }
+
+    #[test]
+    fn test_strict_utf8() {
+        let builder = with_variables(&[("NAME", "wörld")]).strict_utf8(true);
+        let error = render_chunked(builder, b"$NAME\nab\xe9 $NAME", 8 * 1024).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidUtf8 {
+                position: Position {
+                    line: 2,
+                    column: 3,
+                    offset: 8
+                }
+            }
+        ));
+        assert_eq!(error.to_string(), "Invalid UTF-8 on line 2, column 3");
+    }
+
+    #[test]
+    fn test_non_utf8_positions() {
+        let error =
+            render_chunked(with_variables(&[]), b"\xe9\xe9 $MISSING", 8 * 1024).unwrap_err();
+        assert_eq!(
+            error.position(),
+            Some(Position {
+                line: 1,
+                column: 4,
+                offset: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_small_buffers() {
+        let templates: &[&[u8]] = &[
+            b"plain text $NAME and ${NAME} and ${MISSING:-d\xe9faut ${NAME}}\n$$NAME \\$NAME",
+            "ünïcödé ${NAME:+wörld} $ ${ }".as_bytes(),
+            b"${NAME:=value}$NAME${NAME:?required}",
+            b"${#NAME} ${NAME:1:2} ${NAME##*r} ${NAME%%r*} ${NAME//\\/*/$NAME} ${NAME/\xc3\xb6}",
+            b"${NAME | upper |shell} ${MISSING | default(\"d\xc3\xa9faut }\") | url-encode}",
+        ];
+        let builder = with_variables(&[("NAME", "wörld")])
+            .missing(MissingVariablePolicy::Empty)
+            .escape(Escape::Both)
+            .pipelines(true);
+        let render = |template, capacity| {
+            render_chunked(builder.clone(), template, capacity).map_err(|error| error.to_string())
+        };
+        for template in templates {
+            let expected = render(template, 8 * 1024);
+            for capacity in 1..16 {
+                assert_eq!(
+                    render(template, capacity),
+                    expected,
+                    "capacity {}",
+                    capacity
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_word() {
+        let word = "ab ${NAME} ".repeat(10_000);
+        let template = format!("[${{UNSET:-{}}}]", word);
+        let builder = with_variables(&[("NAME", "wörld")]);
+        let output = render_chunked(builder, template.as_bytes(), 7).unwrap();
+        let expected = format!("[{}]", "ab wörld ".repeat(10_000));
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_markers() {
+        let builder =
+            with_variables(&[("NAME", "wörld"), ("HOST", "example.com")]).escape(Escape::Both);
+        let render_markers = |template: &str, markers, capacity| {
+            let output = render_chunked(
+                builder.clone().markers(markers),
+                template.as_bytes(),
+                capacity,
+            )
+            .map_err(|error| error.to_string())?;
+            Ok(String::from_utf8(output).unwrap())
+        };
+        let cases = [
+            (
+                Markers::new("{{", "}}").whitespace(true),
+                "Hello {{ NAME }}, {{NAME}}! {{ MISSING:- default {{ NAME }} }} $NAME {{ }} \\{{ NAME }}",
+                "Hello wörld, wörld! default wörld $NAME {{ }} {{ NAME }}",
+            ),
+            (
+                Markers::new("{{", "}}"),
+                "{{NAME}} {{ NAME }} {{UNSET:-x}} {{UNSET:- x }}",
+                "wörld {{ NAME }} x {{UNSET:- x }}",
+            ),
+            (
+                Markers::new("@", "@"),
+                "url=http://@HOST@/ mail=me@example.com @NAME@@NAME@ @@HOST@",
+                "url=http://example.com/ mail=me@example.com wörldwörld @HOST@",
+            ),
+            (
+                Markers::new("%", "%"),
+                "PATH=%HOST%;%NAME% 100% %UNSET:-sure%",
+                "PATH=example.com;wörld 100% sure",
+            ),
+            (
+                Markers::new("@", "@"),
+                "user@host-1 and admin@x",
+                "user@host-1 and admin@x",
+            ),
+            (
+                Markers::new("@", "@"),
+                "mail me@host:25 or you@there",
+                "mail me@host:25 or you@there",
+            ),
+            (
+                Markers::new("%", "%"),
+                "printf(\"%d:%s\")",
+                "printf(\"%d:%s\")",
+            ),
+            (
+                Markers::new("%", "%"),
+                "%UNSET:-a\\ b% %UNSET:-a\nb%",
+                "a\\ b %UNSET:-a\nb%",
+            ),
+        ];
+        for (markers, template, expected) in &cases {
+            for &capacity in &[1, 2, 3, 5, 8 * 1024] {
+                assert_eq!(
+                    render_markers(template, markers.clone(), capacity),
+                    Ok(expected.to_string()),
+                    "capacity {}",
+                    capacity
+                );
+            }
+        }
+
+        assert_eq!(
+            render_markers("{{UNSET:-x", Markers::new("{{", "}}"), 8 * 1024),
+            Ok("{{UNSET:-x".to_owned())
+        );
+        assert_eq!(
+            render_markers("{{UNSET:-{{NAME}}", Markers::new("{{", "}}"), 8 * 1024),
+            Err(
+                "Failed to parse a variable on line 1, column 1 missing a '}}' after 'UNSET'"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_long_line() {
+        let text = "x".repeat(1 << 20);
+        let template = format!("{}\n{} $NAME {}", text, text, text);
+        let error = render(with_variables(&[]), &template).unwrap_err();
+        assert_eq!(
+            error.position(),
+            Some(Position {
+                line: 2,
+                column: text.len() + 2,
+                offset: 2 * text.len() + 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = ParserBuilder::new()
+            .source(source(&[("HOST", "example.com")]))
+            .delimiter('%')
+            .escape(Escape::Double)
+            .missing(MissingVariablePolicy::Keep);
+
+        assert_eq!(
+            render(builder.clone(), "%HOST %%HOST %{MISSING} $HOST").unwrap(),
+            "example.com %HOST %{MISSING} $HOST"
+        );
+
+        let template = builder.template("%HOST %{MISSING}").unwrap();
+        assert_eq!(
+            template.render_to_string(&source(&[])).unwrap(),
+            "%HOST %{MISSING}"
+        );
+
+        let template = ParserBuilder::new()
+            .missing(MissingVariablePolicy::Fail)
+            .markers(Markers::new("{{", "}}"))
+            .template("{{HOST}} {{MISSING}}")
+            .unwrap();
+        assert_eq!(
+            template
+                .render_to_string(&source(&[]))
+                .unwrap_err()
+                .to_string(),
+            "The variable HOST is not set on line 1, column 1"
         );
     }
 }
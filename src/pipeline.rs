@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "base64")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+#[cfg(feature = "sha2")]
+use sha2::{Digest, Sha256};
+
+use crate::source::SourceError;
+
+/// A filter of a pipeline like `${VAR | upper | default("none")}`, which
+/// turns the value of the variable into a new one.
+///
+/// Any `Fn(&str, &[String]) -> Result<String, SourceError>` is a filter that
+/// leaves variables that are not set alone.
+pub trait ValueFilter {
+    /// Filters the value of a variable that is set. `arguments` are the ones
+    /// between the parentheses after the name of the filter, if any.
+    fn apply(&self, value: &str, arguments: &[String]) -> Result<String, SourceError>;
+
+    /// The value of a variable that is not set once filtered, by default it
+    /// stays unset and the missing variable policy applies after the pipeline.
+    fn apply_missing(&self, arguments: &[String]) -> Result<Option<String>, SourceError> {
+        let _ = arguments;
+        Ok(None)
+    }
+}
+
+impl<F> ValueFilter for F
+where
+    F: Fn(&str, &[String]) -> Result<String, SourceError>,
+{
+    fn apply(&self, value: &str, arguments: &[String]) -> Result<String, SourceError> {
+        self(value, arguments)
+    }
+}
+
+/// The filters that pipelines can use, by name.
+///
+/// `ValueFilters::new` starts with the built-in ones:
+///
+/// - `upper`, `lower` and `trim`;
+/// - `base64` and `base64-decode`, with the standard alphabet and padding,
+///   with the `base64` feature;
+/// - `url-encode`, which percent-encodes everything but unreserved characters;
+/// - `json`, which escapes the value to be written inside a JSON string;
+/// - `yaml`, which quotes the value as a double-quoted YAML scalar;
+/// - `shell`, which quotes the value as a single POSIX shell word;
+/// - `sha256`, the hexadecimal digest of the value, with the `sha2` feature;
+/// - `default("word")`, which uses `word` when the variable is not set or
+///   empty.
+#[derive(Clone)]
+pub struct ValueFilters {
+    filters: BTreeMap<String, Arc<dyn ValueFilter + Send + Sync>>,
+}
+
+impl ValueFilters {
+    pub fn new() -> Self {
+        let mut filters = Self {
+            filters: BTreeMap::new(),
+        };
+        filters.insert("upper", upper);
+        filters.insert("lower", lower);
+        filters.insert("trim", trim);
+        filters.insert("url-encode", url_encode);
+        filters.insert("json", json);
+        filters.insert("yaml", yaml);
+        filters.insert("shell", shell);
+        filters.insert("default", Fallback);
+        #[cfg(feature = "base64")]
+        {
+            filters.insert("base64", base64_encode);
+            filters.insert("base64-decode", base64_decode);
+        }
+        #[cfg(feature = "sha2")]
+        filters.insert("sha256", sha256);
+        filters
+    }
+
+    /// Adds a filter, replacing the one with the same name if there is one.
+    pub fn insert<F>(&mut self, name: &str, filter: F)
+    where
+        F: ValueFilter + Send + Sync + 'static,
+    {
+        self.filters.insert(name.to_owned(), Arc::new(filter));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&(dyn ValueFilter + Send + Sync)> {
+        self.filters.get(name).map(|filter| filter.as_ref())
+    }
+
+    /// The names of the filters, in alphabetical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.filters.keys().map(String::as_str)
+    }
+}
+
+impl Default for ValueFilters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ValueFilters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+fn no_arguments(arguments: &[String]) -> Result<(), SourceError> {
+    if arguments.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("expected no arguments, got {}", arguments.len()).into())
+    }
+}
+
+fn upper(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(value.to_uppercase())
+}
+
+fn lower(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(value.to_lowercase())
+}
+
+fn trim(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(value.trim().to_owned())
+}
+
+#[cfg(feature = "base64")]
+fn base64_encode(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(STANDARD.encode(value))
+}
+
+#[cfg(feature = "base64")]
+fn base64_decode(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    let decoded = STANDARD.decode(value)?;
+    String::from_utf8(decoded).map_err(|_| "the decoded value is not valid UTF-8".into())
+}
+
+fn url_encode(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    Ok(encoded)
+}
+
+fn json(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(escape_json(value))
+}
+
+/// Escapes `value` to be written inside a JSON string, without the quotes.
+pub(crate) fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for current_char in value.chars() {
+        match current_char {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            current_char if current_char.is_control() => {
+                escaped.push_str(&format!("\\u{:04x}", current_char as u32))
+            }
+            current_char => escaped.push(current_char),
+        }
+    }
+    escaped
+}
+
+fn yaml(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    // The escapes of JSON strings are valid in double-quoted YAML scalars.
+    Ok(format!("\"{}\"", json(value, arguments)?))
+}
+
+fn shell(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(format!("'{}'", value.replace('\'', "'\\''")))
+}
+
+#[cfg(feature = "sha2")]
+fn sha256(value: &str, arguments: &[String]) -> Result<String, SourceError> {
+    no_arguments(arguments)?;
+    Ok(Sha256::digest(value)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// `default("word")`, the only built-in filter that sets a missing variable.
+struct Fallback;
+
+impl Fallback {
+    fn word(arguments: &[String]) -> Result<&str, SourceError> {
+        match arguments {
+            [word] => Ok(word),
+            _ => Err(format!("expected one argument, got {}", arguments.len()).into()),
+        }
+    }
+}
+
+impl ValueFilter for Fallback {
+    fn apply(&self, value: &str, arguments: &[String]) -> Result<String, SourceError> {
+        let word = Self::word(arguments)?;
+        Ok(if value.is_empty() { word } else { value }.to_owned())
+    }
+
+    fn apply_missing(&self, arguments: &[String]) -> Result<Option<String>, SourceError> {
+        Ok(Some(Self::word(arguments)?.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::ValueFilters;
+    use crate::source::SourceError;
+
+    fn apply(filter: &str, value: &str) -> String {
+        ValueFilters::new()
+            .get(filter)
+            .unwrap()
+            .apply(value, &[])
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builtin_filters() {
+        assert_eq!(apply("upper", "Grüße"), "GRÜSSE");
+        assert_eq!(apply("lower", "HeLLo"), "hello");
+        assert_eq!(apply("trim", " \tpadded\n"), "padded");
+        assert_eq!(apply("url-encode", "a b&c/é~"), "a%20b%26c%2F%C3%A9~");
+        assert_eq!(
+            apply("json", "say \"hi\"\n\\\u{1}"),
+            "say \\\"hi\\\"\\n\\\\\\u0001"
+        );
+        assert_eq!(apply("yaml", "it's: \"x\""), "\"it's: \\\"x\\\"\"");
+        assert_eq!(apply("shell", "it's"), "'it'\\''s'");
+        assert_eq!(apply("shell", ""), "''");
+    }
+
+    #[test]
+    #[cfg(feature = "base64")]
+    fn test_base64() {
+        for (value, encoded) in &[
+            ("", ""),
+            ("M", "TQ=="),
+            ("Ma", "TWE="),
+            ("Man", "TWFu"),
+            ("hello world", "aGVsbG8gd29ybGQ="),
+        ] {
+            assert_eq!(apply("base64", value), *encoded);
+            assert_eq!(apply("base64-decode", encoded), *value);
+        }
+        let filters = ValueFilters::new();
+        let decode = filters.get("base64-decode").unwrap();
+        assert!(decode.apply("T!==", &[]).is_err());
+        assert!(decode.apply("TWFuT", &[]).is_err());
+        assert!(decode.apply("/w==", &[]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sha2")]
+    fn test_sha256() {
+        assert_eq!(
+            apply("sha256", ""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            apply("sha256", "abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            apply(
+                "sha256",
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_default() {
+        let filters = ValueFilters::new();
+        let default = filters.get("default").unwrap();
+        let word = ["none".to_owned()];
+        assert_eq!(default.apply("set", &word).unwrap(), "set");
+        assert_eq!(default.apply("", &word).unwrap(), "none");
+        assert_eq!(
+            default.apply_missing(&word).unwrap(),
+            Some("none".to_owned())
+        );
+        assert!(default.apply_missing(&[]).is_err());
+        assert!(filters.get("upper").unwrap().apply("x", &word).is_err());
+        assert_eq!(
+            filters.get("upper").unwrap().apply_missing(&[]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut filters = ValueFilters::new();
+        filters.insert(
+            "repeat",
+            |value: &str, arguments: &[String]| -> Result<String, SourceError> {
+                let count = arguments.first().map_or(Ok(2), |count| count.parse())?;
+                Ok(value.repeat(count))
+            },
+        );
+        let repeat = filters.get("repeat").unwrap();
+        assert_eq!(repeat.apply("ab", &[]).unwrap(), "abab");
+        assert_eq!(repeat.apply("ab", &["3".to_owned()]).unwrap(), "ababab");
+        assert!(repeat.apply("ab", &["x".to_owned()]).is_err());
+        assert!(filters.names().any(|name| name == "repeat"));
+        assert!(!ValueFilters::new().names().any(|name| name == "repeat"));
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::env::VarError;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::filter::VariableFilter;
+use crate::glob::Glob;
+use crate::pipeline::ValueFilters;
+use crate::source::VariableSource;
+use crate::syntax::{Expansion, Node, Operator, Variable};
+
+/// What to do with a variable that is not set.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MissingVariablePolicy {
+    /// Replace it with an empty string.
+    #[default]
+    Empty,
+    /// Stop with an error.
+    Fail,
+    /// Keep the reference exactly as it appears in the template, so it can be
+    /// substituted by a later pass.
+    Keep,
+    /// Replace it with an empty string and report it to the handler set with
+    /// `ParserBuilder::on_warning`, if any.
+    Warn,
+}
+
+impl FromStr for MissingVariablePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "empty" => Ok(MissingVariablePolicy::Empty),
+            "fail" => Ok(MissingVariablePolicy::Fail),
+            "keep" => Ok(MissingVariablePolicy::Keep),
+            "warn" => Ok(MissingVariablePolicy::Warn),
+            _ => Err(format!(
+                "invalid policy '{}', expected one of empty, fail, keep or warn",
+                value
+            )),
+        }
+    }
+}
+
+/// What `MissingVariablePolicy::Warn` reports variables that are not set to.
+#[derive(Clone, Default)]
+pub(crate) struct WarningHandler(Option<Arc<Handler>>);
+
+type Handler = dyn Fn(&Variable) + Send + Sync;
+
+impl WarningHandler {
+    pub(crate) fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&Variable) + Send + Sync + 'static,
+    {
+        Self(Some(Arc::new(handler)))
+    }
+
+    fn warn(&self, variable: &Variable) {
+        if let Some(handler) = &self.0 {
+            handler(variable);
+        }
+    }
+}
+
+impl fmt::Debug for WarningHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("WarningHandler")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+/// Writes nodes with the values of their variables.
+pub(crate) struct Renderer<'a, S: ?Sized> {
+    pub(crate) source: &'a S,
+    pub(crate) missing: MissingVariablePolicy,
+    pub(crate) filter: &'a VariableFilter,
+    /// Variables set with `${VAR:=word}`, they take precedence over `source`.
+    /// Their value is only valid UTF-8 if the template is.
+    pub(crate) assigned: &'a mut HashMap<String, Vec<u8>>,
+    /// Whether `${VAR^^}` and the other case operators only change ASCII
+    /// letters.
+    pub(crate) ascii_case: bool,
+    pub(crate) value_filters: &'a ValueFilters,
+    pub(crate) warnings: &'a WarningHandler,
+}
+
+impl<S> Renderer<'_, S>
+where
+    S: VariableSource + ?Sized,
+{
+    pub(crate) fn render<W: Write>(&mut self, nodes: &[Node], output: &mut W) -> Result<()> {
+        for node in nodes {
+            self.render_node(node, output)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn render_node<W: Write>(&mut self, node: &Node, output: &mut W) -> Result<()> {
+        match node {
+            Node::Text { text, .. } => output.write_all(text)?,
+            Node::Variable(variable) => self.render_variable(variable, output)?,
+        }
+        Ok(())
+    }
+
+    fn render_variable<W: Write>(&mut self, variable: &Variable, output: &mut W) -> Result<()> {
+        if !self.filter.matches(&variable.name) {
+            output.write_all(&variable.raw)?;
+            return Ok(());
+        }
+
+        let mut value = self.lookup(variable)?;
+        if !variable.filters.is_empty() {
+            value = self.pipe(variable, value)?;
+        }
+        let expansion = match &variable.expansion {
+            Some(expansion) => expansion,
+            None => {
+                let value = match value {
+                    Some(value) => value,
+                    None => self.missing_value(variable)?,
+                };
+                output.write_all(&value)?;
+                return Ok(());
+            }
+        };
+
+        if expansion.operator.transforms() {
+            let result = match value {
+                Some(value) => self.transform(variable, expansion, &value)?,
+                None if self.missing == MissingVariablePolicy::Keep => variable.raw.clone(),
+                // Like in shells, `${#VAR}` is 0 when VAR is not set.
+                None => {
+                    let value = self.missing_value(variable)?;
+                    self.transform(variable, expansion, &value)?
+                }
+            };
+            output.write_all(&result)?;
+            return Ok(());
+        }
+
+        let value = value.filter(|value| !(expansion.null_check && value.is_empty()));
+        let result = match (expansion.operator, value) {
+            (Operator::Alternate, Some(_)) => self.expand(&expansion.word)?,
+            (Operator::Alternate, None) => Vec::new(),
+            (_, Some(value)) => value,
+            (Operator::UseDefault, None) => self.expand(&expansion.word)?,
+            (Operator::AssignDefault, None) => {
+                let word = self.expand(&expansion.word)?;
+                self.assigned.insert(variable.name.clone(), word.clone());
+                word
+            }
+            (Operator::Required, None) => {
+                let word = self.expand(&expansion.word)?;
+                let message = match (word.is_empty(), expansion.null_check) {
+                    (false, _) => String::from_utf8_lossy(&word).into_owned(),
+                    (true, true) => "parameter null or not set".to_owned(),
+                    (true, false) => "parameter not set".to_owned(),
+                };
+                return Err(Error::RequiredVariable {
+                    name: variable.name.clone(),
+                    message,
+                    position: variable.span.start,
+                });
+            }
+            (_, None) => unreachable!("operators that transform the value are handled above"),
+        };
+
+        output.write_all(&result)?;
+        Ok(())
+    }
+
+    /// What replaces a variable that is not set, if it is not an error.
+    fn missing_value(&self, variable: &Variable) -> Result<Vec<u8>> {
+        match self.missing {
+            MissingVariablePolicy::Empty => Ok(Vec::new()),
+            MissingVariablePolicy::Fail => Err(Error::MissingVariable {
+                name: variable.name.clone(),
+                position: variable.span.start,
+            }),
+            MissingVariablePolicy::Keep => Ok(variable.raw.clone()),
+            MissingVariablePolicy::Warn => {
+                self.warnings.warn(variable);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Applies an operator that changes the value, like `${VAR#pattern}`.
+    /// Values are handled as characters, so the ones that are not valid UTF-8
+    /// are decoded lossily.
+    fn transform(
+        &mut self,
+        variable: &Variable,
+        expansion: &Expansion,
+        value: &[u8],
+    ) -> Result<Vec<u8>> {
+        let value: Vec<char> = String::from_utf8_lossy(value).chars().collect();
+        let result: String = match expansion.operator {
+            Operator::Length => value.len().to_string(),
+            Operator::Substring => {
+                let offset = self.number(variable, &expansion.word)?;
+                let length = match &expansion.argument {
+                    Some(argument) => Some(self.number(variable, argument)?),
+                    None => None,
+                };
+                substring(&value, offset, length).iter().collect()
+            }
+            Operator::RemoveShortestPrefix | Operator::RemoveLongestPrefix => {
+                let ends = self.pattern(&expansion.word)?.match_ends(&value);
+                let end = if expansion.operator == Operator::RemoveShortestPrefix {
+                    ends.iter().position(|matched| *matched)
+                } else {
+                    ends.iter().rposition(|matched| *matched)
+                };
+                value[end.unwrap_or(0)..].iter().collect()
+            }
+            Operator::RemoveShortestSuffix | Operator::RemoveLongestSuffix => {
+                let starts = self.pattern(&expansion.word)?.match_starts(&value);
+                let start = if expansion.operator == Operator::RemoveShortestSuffix {
+                    starts.iter().rposition(|matched| *matched)
+                } else {
+                    starts.iter().position(|matched| *matched)
+                };
+                value[..start.unwrap_or(value.len())].iter().collect()
+            }
+            Operator::ReplaceFirst | Operator::ReplaceAll => {
+                let pattern = self.pattern(&expansion.word)?;
+                let replacement = match &expansion.argument {
+                    Some(argument) => String::from_utf8_lossy(&self.expand(argument)?).into_owned(),
+                    None => String::new(),
+                };
+                let all = expansion.operator == Operator::ReplaceAll;
+                replace(&value, &pattern, &replacement, all)
+            }
+            Operator::UppercaseFirst
+            | Operator::UppercaseAll
+            | Operator::LowercaseFirst
+            | Operator::LowercaseAll => {
+                let pattern = if expansion.word.is_empty() {
+                    None
+                } else {
+                    Some(self.pattern(&expansion.word)?)
+                };
+                let operator = expansion.operator;
+                let all = matches!(operator, Operator::UppercaseAll | Operator::LowercaseAll);
+                let upper = matches!(operator, Operator::UppercaseFirst | Operator::UppercaseAll);
+                change_case(&value, pattern.as_ref(), upper, all, self.ascii_case)
+            }
+            Operator::UseDefault
+            | Operator::AssignDefault
+            | Operator::Required
+            | Operator::Alternate => unreachable!("the value is only transformed by the others"),
+        };
+        Ok(result.into_bytes())
+    }
+
+    fn pattern(&mut self, word: &[Node]) -> Result<Glob> {
+        let pattern = self.expand(word)?;
+        Ok(Glob::shell(&String::from_utf8_lossy(&pattern)))
+    }
+
+    /// The offset or length of `${VAR:offset:length}`, where an empty word is
+    /// 0 like in shells.
+    fn number(&mut self, variable: &Variable, word: &[Node]) -> Result<i64> {
+        let word = String::from_utf8_lossy(&self.expand(word)?).into_owned();
+        let number = word.trim();
+        // `${VAR:(-1)}` is how shells tell a negative offset from `${VAR:-word}`.
+        let number = number
+            .strip_prefix('(')
+            .and_then(|number| number.strip_suffix(')'))
+            .unwrap_or(number)
+            .trim();
+        if number.is_empty() {
+            return Ok(0);
+        }
+        number.parse().map_err(|_| Error::InvalidNumber {
+            name: variable.name.clone(),
+            value: word.clone(),
+            position: variable.span.start,
+        })
+    }
+
+    /// Renders the word of an operator, which can reference other variables
+    /// itself.
+    fn expand(&mut self, word: &[Node]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.render(word, &mut output)?;
+        Ok(output)
+    }
+
+    fn lookup(&self, variable: &Variable) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.assigned.get(&variable.name) {
+            return Ok(Some(value.clone()));
+        }
+        let value = self.source.lookup(&variable.name).map_err(|source| {
+            let name = variable.name.clone();
+            let position = variable.span.start;
+            match source.downcast_ref::<VarError>() {
+                Some(VarError::NotUnicode(_)) => Error::NonUnicodeValue { name, position },
+                _ => Error::Source {
+                    name,
+                    position,
+                    source,
+                },
+            }
+        })?;
+        Ok(value.map(String::into_bytes))
+    }
+
+    /// Passes the value of a variable through its filters, one after the
+    /// other. Values that are not valid UTF-8 are converted lossily first.
+    fn pipe(&self, variable: &Variable, value: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let mut value = value.map(|value| String::from_utf8_lossy(&value).into_owned());
+        for call in &variable.filters {
+            let filter =
+                self.value_filters
+                    .get(&call.name)
+                    .ok_or_else(|| Error::UnknownFilter {
+                        name: variable.name.clone(),
+                        filter: call.name.clone(),
+                        position: variable.span.start,
+                    })?;
+            let filtered = match &value {
+                Some(value) => filter.apply(value, &call.arguments).map(Some),
+                None => filter.apply_missing(&call.arguments),
+            };
+            value = filtered.map_err(|source| Error::Filter {
+                name: variable.name.clone(),
+                filter: call.name.clone(),
+                position: variable.span.start,
+                source,
+            })?;
+        }
+        Ok(value.map(String::into_bytes))
+    }
+}
+
+/// The characters of `${VAR:offset:length}`, nothing if the offset is out of
+/// the value or the length ends before it.
+fn substring(value: &[char], offset: i64, length: Option<i64>) -> &[char] {
+    let count = value.len() as i64;
+    let start = if offset < 0 { count + offset } else { offset };
+    let end = match length {
+        Some(length) if length < 0 => count + length,
+        Some(length) => start.saturating_add(length),
+        None => count,
+    };
+    if start < 0 || start > count || end < start {
+        return &[];
+    }
+    &value[start as usize..end.min(count) as usize]
+}
+
+/// Replaces the longest match of `pattern` at each position, from the start,
+/// only the first one unless `all` is set. Empty matches are ignored.
+fn replace(value: &[char], pattern: &Glob, replacement: &str, all: bool) -> String {
+    let mut result = String::new();
+    let mut index = 0;
+    let mut replaced = false;
+    while index < value.len() {
+        if all || !replaced {
+            let ends = pattern.match_ends(&value[index..]);
+            if let Some(length) = ends
+                .iter()
+                .rposition(|matched| *matched)
+                .filter(|length| *length > 0)
+            {
+                result.push_str(replacement);
+                index += length;
+                replaced = true;
+                continue;
+            }
+        }
+        result.push(value[index]);
+        index += 1;
+    }
+    result
+}
+
+/// Changes the case of the first character, or of all of them if `all` is
+/// set, when they match `pattern`.
+fn change_case(
+    value: &[char],
+    pattern: Option<&Glob>,
+    upper: bool,
+    all: bool,
+    ascii: bool,
+) -> String {
+    let mut result = String::with_capacity(value.len());
+    for (index, &current) in value.iter().enumerate() {
+        let matches = match pattern {
+            Some(pattern) => pattern.matches(current.encode_utf8(&mut [0; 4])),
+            None => true,
+        };
+        if !(all || index == 0) || !matches {
+            result.push(current);
+            continue;
+        }
+        match (upper, ascii) {
+            (true, true) => result.push(current.to_ascii_uppercase()),
+            (false, true) => result.push(current.to_ascii_lowercase()),
+            // A character can become several ones, like 'ß' and "SS".
+            (true, false) => result.extend(current.to_uppercase()),
+            (false, false) => result.extend(current.to_lowercase()),
+        }
+    }
+    result
+}
@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::env::{var, VarError};
+use std::error::Error as StdError;
+use std::fmt;
+use std::hash::BuildHasher;
+
+/// The error of a source that failed to look a variable up.
+pub type SourceError = Box<dyn StdError + Send + Sync>;
+
+/// Somewhere the parser can look the value of a variable up.
+///
+/// Returning `Ok(None)` means the variable is not set, errors are reserved for
+/// sources that failed to answer at all.
+pub trait VariableSource {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError>;
+}
+
+/// The environment of the current process.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Env;
+
+impl VariableSource for Env {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        match var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(error) => Err(Box::new(error)),
+        }
+    }
+}
+
+impl<H: BuildHasher> VariableSource for HashMap<String, String, H> {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        Ok(self.get(name).cloned())
+    }
+}
+
+impl VariableSource for BTreeMap<String, String> {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        Ok(self.get(name).cloned())
+    }
+}
+
+impl<F> VariableSource for F
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        Ok(self(name))
+    }
+}
+
+/// Several sources on top of each other, a variable is looked up in the last
+/// layer first, so each layer overrides the ones added before it.
+///
+/// The layer every variable was found in is recorded, which helps to find out
+/// where a value came from.
+#[derive(Default)]
+pub struct Layered {
+    layers: Vec<(String, Box<dyn VariableSource>)>,
+    /// The name of the layer every variable was found in, `None` for the ones
+    /// that are not set.
+    origins: RefCell<BTreeMap<String, Option<String>>>,
+}
+
+impl Layered {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a layer named `name`, which overrides the previous ones.
+    pub fn layer<S>(mut self, name: &str, source: S) -> Self
+    where
+        S: VariableSource + 'static,
+    {
+        self.layers.push((name.to_owned(), Box::new(source)));
+        self
+    }
+
+    /// Returns the value of the variable and the name of the layer it was
+    /// found in.
+    pub fn lookup_layer(&self, name: &str) -> Result<Option<(&str, String)>, SourceError> {
+        for (layer, source) in self.layers.iter().rev() {
+            if let Some(value) = source.lookup(name)? {
+                return Ok(Some((layer, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The variables looked up so far, with the name of the layer they were
+    /// found in or `None` if they are not set.
+    pub fn origins(&self) -> BTreeMap<String, Option<String>> {
+        self.origins.borrow().clone()
+    }
+}
+
+impl VariableSource for Layered {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        let found = self.lookup_layer(name)?;
+        let origin = found.as_ref().map(|(layer, _)| (*layer).to_owned());
+        self.origins.borrow_mut().insert(name.to_owned(), origin);
+        Ok(found.map(|(_, value)| value))
+    }
+}
+
+/// Lets every render share the same layers, and record the origins of their
+/// variables in one place.
+impl VariableSource for &Layered {
+    fn lookup(&self, name: &str) -> Result<Option<String>, SourceError> {
+        (*self).lookup(name)
+    }
+}
+
+impl fmt::Debug for Layered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let layers: Vec<&str> = self.layers.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("Layered")
+            .field("layers", &layers)
+            .field("origins", &self.origins)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::source::{Layered, SourceError, VariableSource};
+
+    fn source(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_layered() {
+        let layered = Layered::new()
+            .layer("defaults", source(&[("HOST", "localhost"), ("PORT", "80")]))
+            .layer("environment", source(&[("HOST", "example.com")]))
+            .layer("overrides", source(&[("PORT", "8080"), ("EMPTY", "")]));
+
+        assert_eq!(
+            layered.lookup_layer("HOST").unwrap(),
+            Some(("environment", "example.com".to_owned()))
+        );
+        assert_eq!(layered.lookup("PORT").unwrap(), Some("8080".to_owned()));
+        assert_eq!(layered.lookup("EMPTY").unwrap(), Some("".to_owned()));
+        assert_eq!(layered.lookup("MISSING").unwrap(), None);
+
+        let mut origins = BTreeMap::new();
+        origins.insert("PORT".to_owned(), Some("overrides".to_owned()));
+        origins.insert("EMPTY".to_owned(), Some("overrides".to_owned()));
+        origins.insert("MISSING".to_owned(), None);
+        assert_eq!(layered.origins(), origins);
+
+        let borrowed = &layered;
+        assert_eq!(
+            VariableSource::lookup(&borrowed, "HOST").unwrap(),
+            Some("example.com".to_owned())
+        );
+        origins.insert("HOST".to_owned(), Some("environment".to_owned()));
+        assert_eq!(layered.origins(), origins);
+    }
+
+    struct Failing;
+
+    impl VariableSource for Failing {
+        fn lookup(&self, _: &str) -> Result<Option<String>, SourceError> {
+            Err("unreachable".into())
+        }
+    }
+
+    #[test]
+    fn test_layered_error() {
+        let layered = Layered::new()
+            .layer("failing", Failing)
+            .layer("overrides", source(&[("SET", "1")]));
+        assert_eq!(layered.lookup("SET").unwrap(), Some("1".to_owned()));
+        assert_eq!(
+            layered.lookup("OTHER").unwrap_err().to_string(),
+            "unreachable"
+        );
+    }
+}
@@ -0,0 +1,1190 @@
+use std::fmt;
+use std::str::{self, FromStr};
+
+use memchr::{memchr, memchr2, memchr3, memchr_iter, memrchr};
+
+use crate::error::{Error, Position, Result};
+use crate::parser::default_delimiter;
+
+const START: u8 = b'{';
+const END: u8 = b'}';
+const UNDERSCORE: char = b'_' as char;
+const COLON: u8 = b':';
+const BACKSLASH: u8 = b'\\';
+const HASH: u8 = b'#';
+const PERCENT: u8 = b'%';
+const SLASH: u8 = b'/';
+const CARET: u8 = b'^';
+const COMMA: u8 = b',';
+const PIPE: u8 = b'|';
+
+/// Which characters variable names are made of. Names between braces can
+/// contain `.` after the first character in every mode, e.g.
+/// `${database.host}`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Identifiers {
+    /// A letter or `_` followed by letters, digits or `_`, where letters and
+    /// digits can be any Unicode ones.
+    Unicode,
+    /// `[A-Za-z_][A-Za-z0-9_]*`, like in POSIX shells.
+    #[default]
+    Ascii,
+    /// Same as `Unicode`, but `.` and `-` are allowed after the first
+    /// character, also without braces, e.g. `$database.host`. `${VAR-word}` is
+    /// then a variable name and not an operator.
+    Extended,
+}
+
+impl Identifiers {
+    fn is_start(self, current_char: char) -> bool {
+        match self {
+            Identifiers::Ascii => current_char == UNDERSCORE || current_char.is_ascii_alphabetic(),
+            _ => current_char == UNDERSCORE || current_char.is_alphabetic(),
+        }
+    }
+
+    fn is_continue(self, current_char: char) -> bool {
+        match self {
+            Identifiers::Ascii => {
+                current_char == UNDERSCORE || current_char.is_ascii_alphanumeric()
+            }
+            Identifiers::Unicode => current_char == UNDERSCORE || current_char.is_alphanumeric(),
+            Identifiers::Extended => {
+                matches!(current_char, '_' | '.' | '-') || current_char.is_alphanumeric()
+            }
+        }
+    }
+}
+
+impl FromStr for Identifiers {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "unicode" => Ok(Identifiers::Unicode),
+            "ascii" => Ok(Identifiers::Ascii),
+            "extended" => Ok(Identifiers::Extended),
+            _ => Err(format!(
+                "invalid identifiers '{}', expected one of unicode, ascii or extended",
+                value
+            )),
+        }
+    }
+}
+
+/// How a literal delimiter can be written in a template.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Escape {
+    /// Every delimiter followed by a variable name starts a variable.
+    #[default]
+    None,
+    /// `$$` is written as `$`.
+    Double,
+    /// `\$` is written as `$`, a backslash before anything else is kept.
+    Backslash,
+    /// Both `$$` and `\$` are written as `$`.
+    Both,
+}
+
+impl Escape {
+    fn double(self) -> bool {
+        self == Escape::Double || self == Escape::Both
+    }
+
+    fn backslash(self) -> bool {
+        self == Escape::Backslash || self == Escape::Both
+    }
+}
+
+impl FromStr for Escape {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Escape::None),
+            "double" => Ok(Escape::Double),
+            "backslash" => Ok(Escape::Backslash),
+            "both" => Ok(Escape::Both),
+            _ => Err(format!(
+                "invalid escape '{}', expected one of none, double, backslash or both",
+                value
+            )),
+        }
+    }
+}
+
+/// Custom markers around variables, like `{{` and `}}` for `{{NAME}}`, `@` and
+/// `@` for autoconf's `@NAME@` or `%` and `%` for `%NAME%`.
+///
+/// Variables are then always written between the markers, with an optional
+/// operator like in `{{NAME:-word}}`, and escapes apply to the opening marker
+/// instead of the delimiter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Markers {
+    open: String,
+    close: String,
+    whitespace: bool,
+}
+
+impl Markers {
+    /// # Panics
+    ///
+    /// If one of the markers is empty.
+    pub fn new(open: &str, close: &str) -> Self {
+        assert!(
+            !open.is_empty() && !close.is_empty(),
+            "markers can't be empty"
+        );
+        Self {
+            open: open.to_owned(),
+            close: close.to_owned(),
+            whitespace: false,
+        }
+    }
+
+    /// Allows whitespace right inside the markers, like in `{{ NAME }}`. The
+    /// word of an operator is then trimmed, `{{ NAME:- word }}` defaults to
+    /// `word`.
+    pub fn whitespace(mut self, whitespace: bool) -> Self {
+        self.whitespace = whitespace;
+        self
+    }
+}
+
+/// Everything that decides how a template is split into nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Syntax {
+    pub(crate) delimiter: char,
+    pub(crate) escape: Escape,
+    pub(crate) identifiers: Identifiers,
+    /// Whether text that is not valid UTF-8 is an error instead of being
+    /// written as it is.
+    pub(crate) strict_utf8: bool,
+    /// Whether only `${NAME}` starts a variable, `$NAME` is then written as it
+    /// is.
+    pub(crate) braced_only: bool,
+    /// Replaces the delimiter and the braces when set.
+    pub(crate) markers: Option<Markers>,
+    /// Whether `${NAME | filter}` pipelines are parsed.
+    pub(crate) pipelines: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self {
+            delimiter: default_delimiter(),
+            escape: Escape::default(),
+            identifiers: Identifiers::default(),
+            strict_utf8: false,
+            braced_only: false,
+            markers: None,
+            pipelines: false,
+        }
+    }
+}
+
+impl Syntax {
+    /// What every variable starts with, the delimiter or the opening marker.
+    fn lead(&self) -> Vec<u8> {
+        match &self.markers {
+            Some(markers) => markers.open.as_bytes().to_owned(),
+            None => self.delimiter.to_string().into_bytes(),
+        }
+    }
+
+    /// What ends a variable that has braces or markers.
+    fn close(&self) -> &[u8] {
+        match &self.markers {
+            Some(markers) => markers.close.as_bytes(),
+            None => &[END],
+        }
+    }
+
+    fn whitespace(&self) -> bool {
+        matches!(&self.markers, Some(markers) if markers.whitespace)
+    }
+}
+
+/// Where a node is in its template, `end` is the position right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// A piece of a template.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// Text that is written as it is, escapes are already resolved. It is only
+    /// valid UTF-8 if the template is.
+    Text {
+        text: Vec<u8>,
+        span: Span,
+    },
+    Variable(Variable),
+}
+
+/// A reference to a variable, like `$NAME`, `${NAME}` or `${NAME:-word}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    /// Whether the name is surrounded by braces.
+    pub braced: bool,
+    pub expansion: Option<Expansion>,
+    /// The filters of `${NAME | filter}`, applied in order. A variable has
+    /// either filters or an expansion.
+    pub filters: Vec<FilterCall>,
+    /// The reference exactly as it is written in the template.
+    pub raw: Vec<u8>,
+    pub span: Span,
+}
+
+/// A filter of a pipeline, like `default("none")` in
+/// `${NAME | default("none")}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterCall {
+    pub name: String,
+    /// The arguments between the parentheses, with their quotes removed.
+    pub arguments: Vec<String>,
+}
+
+/// The operator of a braced variable and its word, e.g. `:-default`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub operator: Operator,
+    /// Whether an empty variable is handled like an unset one, which is what
+    /// the ':' in front of the operator means.
+    pub null_check: bool,
+    /// The default value, the message, the pattern or the offset, depending
+    /// on the operator.
+    pub word: Vec<Node>,
+    /// The replacement of `${VAR/pattern/replacement}` or the length of
+    /// `${VAR:offset:length}`, when there is one.
+    pub argument: Option<Vec<Node>>,
+    /// The words exactly as they are written in the template, including the
+    /// separator before the argument.
+    pub raw_word: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    /// `${VAR-word}`: use `word` when the variable is not set.
+    UseDefault,
+    /// `${VAR=word}`: same as `UseDefault`, but `VAR` keeps the value for the
+    /// rest of the template.
+    AssignDefault,
+    /// `${VAR?word}`: fail with `word` as the message when the variable is not
+    /// set.
+    Required,
+    /// `${VAR+word}`: use `word` when the variable is set, nothing otherwise.
+    Alternate,
+    /// `${#VAR}`: the number of characters of the value.
+    Length,
+    /// `${VAR:offset}` or `${VAR:offset:length}`: a part of the value, where
+    /// a negative offset or length counts from the end.
+    Substring,
+    /// `${VAR#pattern}`: remove the shortest prefix matching a glob pattern.
+    RemoveShortestPrefix,
+    /// `${VAR##pattern}`: remove the longest prefix matching a glob pattern.
+    RemoveLongestPrefix,
+    /// `${VAR%pattern}`: remove the shortest suffix matching a glob pattern.
+    RemoveShortestSuffix,
+    /// `${VAR%%pattern}`: remove the longest suffix matching a glob pattern.
+    RemoveLongestSuffix,
+    /// `${VAR/pattern/replacement}`: replace the longest match of a glob
+    /// pattern, or remove it when there is no replacement.
+    ReplaceFirst,
+    /// `${VAR//pattern/replacement}`: same as `ReplaceFirst`, but for every
+    /// match.
+    ReplaceAll,
+    /// `${VAR^}`: uppercase the first character, or `${VAR^pattern}` only if
+    /// it matches a glob pattern.
+    UppercaseFirst,
+    /// `${VAR^^}`: uppercase every character, or the ones matching
+    /// `${VAR^^pattern}`.
+    UppercaseAll,
+    /// `${VAR,}`: lowercase the first character.
+    LowercaseFirst,
+    /// `${VAR,,}`: lowercase every character.
+    LowercaseAll,
+}
+
+impl fmt::Display for Expansion {
+    /// Writes the operator as it appears in the template, e.g. `:-`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.null_check {
+            write!(f, "{}", COLON as char)?;
+        }
+        let operator = match self.operator {
+            Operator::UseDefault => "-",
+            Operator::AssignDefault => "=",
+            Operator::Required => "?",
+            Operator::Alternate => "+",
+            Operator::Length | Operator::RemoveShortestPrefix => "#",
+            Operator::Substring => ":",
+            Operator::RemoveLongestPrefix => "##",
+            Operator::RemoveShortestSuffix => "%",
+            Operator::RemoveLongestSuffix => "%%",
+            Operator::ReplaceFirst => "/",
+            Operator::ReplaceAll => "//",
+            Operator::UppercaseFirst => "^",
+            Operator::UppercaseAll => "^^",
+            Operator::LowercaseFirst => ",",
+            Operator::LowercaseAll => ",,",
+        };
+        write!(f, "{}", operator)
+    }
+}
+
+impl Operator {
+    /// Whether the operator changes the value of the variable, instead of
+    /// handling the case where it is not set.
+    pub fn transforms(self) -> bool {
+        !matches!(
+            self,
+            Operator::UseDefault
+                | Operator::AssignDefault
+                | Operator::Required
+                | Operator::Alternate
+        )
+    }
+
+    /// The operators that can follow a ':', only `Substring` can't.
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'-' => Some(Operator::UseDefault),
+            b'=' => Some(Operator::AssignDefault),
+            b'?' => Some(Operator::Required),
+            b'+' => Some(Operator::Alternate),
+            _ => None,
+        }
+    }
+}
+
+/// Adds every variable in `nodes` to `found`, including the ones in the words
+/// of operators, in the order they appear in the template.
+pub(crate) fn collect_variables<'a>(nodes: &'a [Node], found: &mut Vec<&'a Variable>) {
+    for node in nodes {
+        if let Node::Variable(variable) = node {
+            found.push(variable);
+            if let Some(expansion) = &variable.expansion {
+                collect_variables(&expansion.word, found);
+                if let Some(argument) = &expansion.argument {
+                    collect_variables(argument, found);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `byte` can follow the name of a braced variable, before the
+/// closing brace.
+fn starts_operator(byte: u8) -> bool {
+    matches!(byte, COLON | HASH | PERCENT | SLASH | CARET | COMMA)
+        || Operator::from_byte(byte).is_some()
+}
+
+fn is_filter_name(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-'
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
+}
+
+/// The UTF-8 character at the start of some bytes.
+enum Decoded {
+    Char(char),
+    Invalid,
+    /// The bytes end in the middle of the character.
+    Truncated,
+}
+
+/// Returns `None` if `bytes` is empty.
+fn decode(bytes: &[u8]) -> Option<Decoded> {
+    let bytes = &bytes[..bytes.len().min(4)];
+    let valid = match str::from_utf8(bytes) {
+        Ok(valid) => valid,
+        Err(error) if error.valid_up_to() > 0 => {
+            str::from_utf8(&bytes[..error.valid_up_to()]).expect("checked by from_utf8")
+        }
+        Err(error) if error.error_len().is_none() => return Some(Decoded::Truncated),
+        Err(_) => return Some(Decoded::Invalid),
+    };
+    valid.chars().next().map(Decoded::Char)
+}
+
+enum LexError {
+    /// The text ended in the middle of a node, but more of it may follow.
+    Incomplete,
+    Error(Error),
+}
+
+impl From<Error> for LexError {
+    fn from(error: Error) -> Self {
+        LexError::Error(error)
+    }
+}
+
+/// Removes the whitespace around a word, which is only there to make the
+/// template easier to read when it is allowed inside the markers.
+fn trim_word(word: &mut Vec<Node>) {
+    if let Some(Node::Text { text, .. }) = word.first_mut() {
+        let start = text
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(text.len());
+        text.drain(..start);
+    }
+    if let Some(Node::Text { text, .. }) = word.last_mut() {
+        let end = text
+            .iter()
+            .rposition(|byte| !byte.is_ascii_whitespace())
+            .map_or(0, |last| last + 1);
+        text.truncate(end);
+    }
+    word.retain(|node| !matches!(node, Node::Text { text, .. } if text.is_empty()));
+}
+
+/// Splits a template into nodes.
+///
+/// The text can be a part of the template only, as long as it starts at the
+/// beginning of a node. Unless `complete` is set, nodes that may continue after
+/// the end of the text are left for when more of it is available.
+///
+/// Only the delimiter, the braces, the operators and variable names have to be
+/// valid UTF-8, other bytes are kept as they are unless `strict_utf8` is set.
+pub(crate) struct Lexer<'a> {
+    text: &'a [u8],
+    syntax: &'a Syntax,
+    /// `syntax.lead()`, which every variable starts with.
+    lead: Vec<u8>,
+    complete: bool,
+    /// Index in `text` of the next byte.
+    index: usize,
+    position: Position,
+}
+
+impl<'a> Lexer<'a> {
+    pub(crate) fn new(
+        text: &'a [u8],
+        syntax: &'a Syntax,
+        complete: bool,
+        position: Position,
+    ) -> Self {
+        Self {
+            text,
+            syntax,
+            lead: syntax.lead(),
+            complete,
+            index: 0,
+            position,
+        }
+    }
+
+    /// Number of bytes of the text that were turned into nodes.
+    pub(crate) fn consumed(&self) -> usize {
+        self.index
+    }
+
+    /// Position of the first byte of the text that is not part of a node yet.
+    pub(crate) fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Consumes the text up to the next byte that may start something else
+    /// than plain text, like a variable or an escape, and returns it as it is.
+    /// This is much faster than `next_node` for long runs of text.
+    pub(crate) fn literal(&mut self) -> &'a [u8] {
+        let start = self.index;
+        let end = self.literal_end(false, None);
+        self.skip(end);
+        &self.text[start..end]
+    }
+
+    /// Returns `None` once the text is consumed, or when the next node needs
+    /// more text to be complete.
+    pub(crate) fn next_node(&mut self) -> Result<Option<Node>> {
+        let (index, position) = (self.index, self.position);
+        match self.node(false, None) {
+            Ok(node) => Ok(node),
+            Err(LexError::Incomplete) => {
+                self.index = index;
+                self.position = position;
+                Ok(None)
+            }
+            Err(LexError::Error(error)) => Err(error),
+        }
+    }
+
+    /// Returns `None` at the end of the text, or at the closing brace of the
+    /// enclosing variable when parsing a word. `stop` is the byte that ends a
+    /// word before its argument, like the `/` of `${VAR/pattern/replacement}`.
+    fn node(&mut self, in_word: bool, stop: Option<u8>) -> Result<Option<Node>, LexError> {
+        if self.peek().is_none() || self.peek() == stop || in_word && self.closes()? {
+            return Ok(None);
+        }
+        if self.starts_variable()? {
+            return Ok(Some(Node::Variable(self.variable()?)));
+        }
+
+        let start = self.position;
+        let text = self.text_node(in_word, stop)?;
+        Ok(Some(Node::Text {
+            text,
+            span: Span {
+                start,
+                end: self.position,
+            },
+        }))
+    }
+
+    fn starts_variable(&self) -> Result<bool, LexError> {
+        match self.marker_at(self.index, &self.lead) {
+            Some(true) => {}
+            None if !self.complete => return Err(LexError::Incomplete),
+            _ => return Ok(false),
+        }
+        let next = self.whitespace_end(self.index + self.lead.len());
+        match decode(&self.text[next..]) {
+            None if self.complete => Ok(false),
+            None => Err(LexError::Incomplete),
+            Some(Decoded::Char(_)) if self.syntax.markers.is_some() => self.marks_variable(next),
+            Some(Decoded::Char(next)) if next == START as char => Ok(true),
+            Some(Decoded::Char(next)) => {
+                Ok(!self.syntax.braced_only && self.syntax.identifiers.is_start(next))
+            }
+            Some(Decoded::Truncated) if !self.complete => Err(LexError::Incomplete),
+            Some(_) => Ok(false),
+        }
+    }
+
+    /// Whether a name followed by the closing marker or an operator starts at
+    /// `index`. Markers are common in text, like the `%` of `100% sure` or
+    /// the `@` of an email address, which are then left as they are.
+    ///
+    /// An operator only counts when the closing marker follows it on the same
+    /// line, without whitespace in between unless the markers allow it, so
+    /// `user@host-1 and admin@x` stays text.
+    fn marks_variable(&self, index: usize) -> Result<bool, LexError> {
+        let mut end = index;
+        loop {
+            let current_char = match decode(&self.text[end..]) {
+                Some(Decoded::Char(current_char)) => current_char,
+                Some(Decoded::Truncated) if !self.complete => return Err(LexError::Incomplete),
+                _ => break,
+            };
+            let valid = if end == index {
+                self.syntax.identifiers.is_start(current_char)
+            } else {
+                self.syntax.identifiers.is_continue(current_char)
+            };
+            if !valid {
+                break;
+            }
+            end += current_char.len_utf8();
+        }
+        if end == index {
+            return Ok(false);
+        }
+
+        let end = self.whitespace_end(end);
+        match self.marker_at(end, self.syntax.close()) {
+            Some(true) => return Ok(true),
+            None if !self.complete => return Err(LexError::Incomplete),
+            _ => {}
+        }
+        if self.syntax.pipelines {
+            let rest = &self.text[end..];
+            match rest.iter().position(|byte| !matches!(byte, b' ' | b'\t')) {
+                Some(pipe) if rest[pipe] == PIPE => return Ok(true),
+                None if !self.complete => return Err(LexError::Incomplete),
+                _ => {}
+            }
+        }
+        match self.text.get(end) {
+            Some(&byte) if starts_operator(byte) => self.closes_operator(end),
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether the closing marker follows the operator at `index` on the same
+    /// run of text. An empty substring like the `%d:%` of `printf("%d:%s")`
+    /// doesn't count.
+    fn closes_operator(&self, index: usize) -> Result<bool, LexError> {
+        let close = self.syntax.close();
+        if self.text[index] == COLON && self.marker_at(index + 1, close) != Some(false) {
+            return match self.marker_at(index + 1, close) {
+                None if !self.complete => Err(LexError::Incomplete),
+                _ => Ok(false),
+            };
+        }
+        let mut end = index + 1;
+        loop {
+            match self.marker_at(end, close) {
+                Some(true) => return Ok(true),
+                None if !self.complete => return Err(LexError::Incomplete),
+                None => return Ok(false),
+                Some(false) => {}
+            }
+            match self.text[end] {
+                b'\n' => return Ok(false),
+                byte if byte.is_ascii_whitespace() && !self.syntax.whitespace() => {
+                    return Ok(false)
+                }
+                BACKSLASH => end += 2,
+                _ => end += 1,
+            }
+        }
+    }
+
+    fn text_node(&mut self, in_word: bool, stop: Option<u8>) -> Result<Vec<u8>, LexError> {
+        let mut text = Vec::new();
+        while let Some(byte) = self.peek() {
+            let end = self.literal_end(in_word, stop);
+            if end > self.index {
+                text.extend_from_slice(&self.text[self.index..end]);
+                self.skip(end);
+                continue;
+            }
+            if Some(byte) == stop {
+                break;
+            }
+            if in_word {
+                match self.marker_at(self.index, self.syntax.close()) {
+                    Some(true) => break,
+                    None if !self.complete => {
+                        if text.is_empty() {
+                            return Err(LexError::Incomplete);
+                        }
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            let lead_length = self.lead.len();
+            let escape_length = if self.syntax.escape.double()
+                && self.marker_at(self.index, &self.lead) == Some(true)
+            {
+                Some(lead_length)
+            } else if byte == BACKSLASH && self.syntax.escape.backslash() {
+                Some(1)
+            } else {
+                None
+            };
+            if let Some(escape_length) = escape_length {
+                match self.marker_at(self.index + escape_length, &self.lead) {
+                    None if !self.complete => {
+                        if text.is_empty() {
+                            return Err(LexError::Incomplete);
+                        }
+                        break;
+                    }
+                    Some(true) => {
+                        let escaped = self.index + escape_length;
+                        text.extend_from_slice(&self.text[escaped..escaped + lead_length]);
+                        for _ in 0..escape_length + lead_length {
+                            self.bump();
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            // A backslash keeps `stop` in the word, like in `${PATH//\//:}`,
+            // the pattern then handles the escape.
+            if byte == BACKSLASH && stop.is_some() {
+                match self.text.get(self.index + 1) {
+                    None if !self.complete => {
+                        if text.is_empty() {
+                            return Err(LexError::Incomplete);
+                        }
+                        break;
+                    }
+                    Some(&next) if Some(next) == stop => {
+                        text.push(self.bump());
+                        text.push(self.bump());
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            if !text.is_empty() && self.starts_variable().unwrap_or(true) {
+                break;
+            }
+            if !byte.is_ascii() && self.syntax.strict_utf8 {
+                let length = match decode(&self.text[self.index..]) {
+                    Some(Decoded::Char(current_char)) => current_char.len_utf8(),
+                    Some(Decoded::Truncated) if !self.complete => {
+                        if text.is_empty() {
+                            return Err(LexError::Incomplete);
+                        }
+                        break;
+                    }
+                    _ => {
+                        return Err(LexError::Error(Error::InvalidUtf8 {
+                            position: self.position,
+                        }))
+                    }
+                };
+                for _ in 0..length {
+                    text.push(self.bump());
+                }
+                continue;
+            }
+            text.push(self.bump());
+        }
+        Ok(text)
+    }
+
+    fn variable(&mut self) -> Result<Variable, LexError> {
+        let start = (self.index, self.position);
+        for _ in 0..self.lead.len() {
+            self.bump();
+        }
+        let braced = self.syntax.markers.is_some() || self.peek() == Some(START);
+        if braced && self.syntax.markers.is_none() {
+            self.bump();
+        }
+        self.skip_whitespace();
+        let length = braced && self.syntax.markers.is_none() && self.peek() == Some(HASH);
+        if length {
+            self.bump();
+        }
+
+        let mut name = String::new();
+        loop {
+            let current_char = match decode(&self.text[self.index..]) {
+                Some(Decoded::Char(current_char)) => current_char,
+                Some(Decoded::Truncated) if !self.complete => return Err(LexError::Incomplete),
+                _ => break,
+            };
+            let valid = if name.is_empty() {
+                self.syntax.identifiers.is_start(current_char)
+            } else {
+                self.syntax.identifiers.is_continue(current_char)
+                    || current_char == '.' && braced && self.syntax.markers.is_none()
+            };
+            if !valid {
+                break;
+            }
+            name.push(current_char);
+            for _ in 0..current_char.len_utf8() {
+                self.bump();
+            }
+        }
+
+        let filters = if braced && !length {
+            self.pipeline(&name, start.1)?
+        } else {
+            Vec::new()
+        };
+        let expansion = if !filters.is_empty() {
+            if !self.closes()? {
+                return Err(match self.peek() {
+                    Some(_) => self.invalid_character(&name, start.1),
+                    None => self.unclosed_brace(&name, start.1),
+                });
+            }
+            self.skip_close();
+            None
+        } else if braced {
+            self.braces_end(&name, length, start.1)?
+        } else {
+            if self.peek().is_none() && !self.complete {
+                return Err(LexError::Incomplete);
+            }
+            None
+        };
+
+        Ok(Variable {
+            name,
+            braced,
+            expansion,
+            filters,
+            raw: self.text[start.0..self.index].to_owned(),
+            span: Span {
+                start: start.1,
+                end: self.position,
+            },
+        })
+    }
+
+    /// Parses what follows the name of a braced variable, up to and including
+    /// the closing brace. `length` is whether the name follows a `#`.
+    fn braces_end(
+        &mut self,
+        name: &str,
+        length: bool,
+        start: Position,
+    ) -> Result<Option<Expansion>, LexError> {
+        self.skip_whitespace();
+        if !name.is_empty() && self.closes()? {
+            self.skip_close();
+            return Ok(if length {
+                Some(Expansion {
+                    operator: Operator::Length,
+                    null_check: false,
+                    word: Vec::new(),
+                    argument: None,
+                    raw_word: Vec::new(),
+                })
+            } else {
+                None
+            });
+        }
+        let byte = match self.peek() {
+            Some(byte) if !name.is_empty() && !length && starts_operator(byte) => byte,
+            Some(_) => return Err(self.invalid_character(name, start)),
+            None => return Err(self.unclosed_brace(name, start)),
+        };
+        self.bump();
+        let (operator, null_check) = match byte {
+            COLON => match self.peek().and_then(Operator::from_byte) {
+                Some(operator) => {
+                    self.bump();
+                    (operator, true)
+                }
+                None => (Operator::Substring, false),
+            },
+            HASH => (
+                self.doubled(HASH, Operator::RemoveLongestPrefix)
+                    .unwrap_or(Operator::RemoveShortestPrefix),
+                false,
+            ),
+            PERCENT => (
+                self.doubled(PERCENT, Operator::RemoveLongestSuffix)
+                    .unwrap_or(Operator::RemoveShortestSuffix),
+                false,
+            ),
+            SLASH => (
+                self.doubled(SLASH, Operator::ReplaceAll)
+                    .unwrap_or(Operator::ReplaceFirst),
+                false,
+            ),
+            CARET => (
+                self.doubled(CARET, Operator::UppercaseAll)
+                    .unwrap_or(Operator::UppercaseFirst),
+                false,
+            ),
+            COMMA => (
+                self.doubled(COMMA, Operator::LowercaseAll)
+                    .unwrap_or(Operator::LowercaseFirst),
+                false,
+            ),
+            _ => (
+                Operator::from_byte(byte).expect("checked by starts_operator"),
+                false,
+            ),
+        };
+
+        let stop = match operator {
+            Operator::Substring => Some(COLON),
+            Operator::ReplaceFirst | Operator::ReplaceAll => Some(SLASH),
+            _ => None,
+        };
+        let word_start = self.index;
+        let mut word = self.word(stop)?;
+        let mut argument = match stop {
+            Some(stop) if self.peek() == Some(stop) => {
+                self.bump();
+                Some(self.word(None)?)
+            }
+            _ => None,
+        };
+        if self.peek().is_none() || !self.closes()? {
+            return Err(self.unclosed_brace(name, start));
+        }
+        let raw_word = self.text[word_start..self.index].to_owned();
+        self.skip_close();
+        if self.syntax.whitespace() {
+            trim_word(&mut word);
+            argument.iter_mut().for_each(trim_word);
+        }
+
+        Ok(Some(Expansion {
+            operator,
+            null_check,
+            word,
+            argument,
+            raw_word,
+        }))
+    }
+
+    /// Parses the filters of `${NAME | filter | filter("argument")}` when
+    /// pipelines are enabled. Nothing is consumed when there are none.
+    fn pipeline(&mut self, name: &str, start: Position) -> Result<Vec<FilterCall>, LexError> {
+        let mut filters = Vec::new();
+        if !self.syntax.pipelines || name.is_empty() {
+            return Ok(filters);
+        }
+        let (index, position) = (self.index, self.position);
+        loop {
+            self.skip_blanks();
+            match self.peek() {
+                Some(PIPE) => {
+                    self.bump();
+                }
+                Some(_) if filters.is_empty() => {
+                    self.index = index;
+                    self.position = position;
+                    return Ok(filters);
+                }
+                Some(_) => return Ok(filters),
+                None => return Err(self.unclosed_brace(name, start)),
+            }
+            self.skip_blanks();
+            let filter_start = self.index;
+            while matches!(self.peek(), Some(byte) if is_filter_name(byte)) {
+                self.bump();
+            }
+            if self.index == filter_start {
+                return Err(match self.peek() {
+                    Some(_) => self.invalid_character(name, start),
+                    None => self.unclosed_brace(name, start),
+                });
+            }
+            let filter = str::from_utf8(&self.text[filter_start..self.index])
+                .expect("filter names are ASCII")
+                .to_owned();
+            self.skip_blanks();
+            let arguments = if self.peek() == Some(b'(') {
+                self.bump();
+                self.arguments(name, start)?
+            } else {
+                Vec::new()
+            };
+            filters.push(FilterCall {
+                name: filter,
+                arguments,
+            });
+        }
+    }
+
+    /// Parses the arguments of a filter, from right after the opening
+    /// parenthesis to the closing one. They are either quoted, or bare words
+    /// like `8`.
+    fn arguments(&mut self, name: &str, start: Position) -> Result<Vec<String>, LexError> {
+        let mut arguments = Vec::new();
+        self.skip_blanks();
+        if self.peek() == Some(b')') {
+            self.bump();
+            return Ok(arguments);
+        }
+        loop {
+            self.skip_blanks();
+            let mut argument = Vec::new();
+            match self.peek() {
+                Some(quote) if quote == b'"' || quote == b'\'' => {
+                    self.bump();
+                    loop {
+                        match self.peek() {
+                            Some(byte) if byte == quote => break,
+                            Some(BACKSLASH) if quote == b'"' => {
+                                self.bump();
+                                if self.peek().is_none() {
+                                    return Err(self.unclosed_brace(name, start));
+                                }
+                            }
+                            Some(_) => {}
+                            None => return Err(self.unclosed_brace(name, start)),
+                        }
+                        argument.push(self.bump());
+                    }
+                    self.bump();
+                }
+                Some(_) => {
+                    let close = self.syntax.close()[0];
+                    while let Some(byte) = self.peek() {
+                        if matches!(byte, b' ' | b'\t' | b',' | b')' | PIPE) || byte == close {
+                            break;
+                        }
+                        argument.push(self.bump());
+                    }
+                    if argument.is_empty() {
+                        return Err(self.invalid_character(name, start));
+                    }
+                }
+                None => return Err(self.unclosed_brace(name, start)),
+            }
+            arguments.push(String::from_utf8_lossy(&argument).into_owned());
+
+            self.skip_blanks();
+            match self.peek() {
+                Some(b',') => {
+                    self.bump();
+                }
+                Some(b')') => {
+                    self.bump();
+                    return Ok(arguments);
+                }
+                Some(_) => return Err(self.invalid_character(name, start)),
+                None => return Err(self.unclosed_brace(name, start)),
+            }
+        }
+    }
+
+    /// Consumes `byte` if it is next, for operators like `##` that have a
+    /// single and a doubled form.
+    fn doubled(&mut self, byte: u8, operator: Operator) -> Option<Operator> {
+        if self.peek() != Some(byte) {
+            return None;
+        }
+        self.bump();
+        Some(operator)
+    }
+
+    /// The nodes of a word, up to the closing brace or `stop`.
+    fn word(&mut self, stop: Option<u8>) -> Result<Vec<Node>, LexError> {
+        let mut word = Vec::new();
+        while let Some(node) = self.node(true, stop)? {
+            word.push(node);
+        }
+        Ok(word)
+    }
+
+    /// The error for the next character, which has to be there.
+    fn invalid_character(&self, name: &str, position: Position) -> LexError {
+        LexError::Error(Error::InvalidCharacter {
+            name: name.to_owned(),
+            character: self.peek_char().unwrap_or(char::REPLACEMENT_CHARACTER),
+            position,
+        })
+    }
+
+    fn unclosed_brace(&self, name: &str, position: Position) -> LexError {
+        if !self.complete {
+            return LexError::Incomplete;
+        }
+        let name = name.to_owned();
+        LexError::Error(match &self.syntax.markers {
+            Some(markers) => Error::UnclosedMarker {
+                name,
+                close: markers.close.clone(),
+                position,
+            },
+            None => Error::UnclosedBrace { name, position },
+        })
+    }
+
+    /// Index of the first byte from the next one that may have to be handled
+    /// by something else than a plain copy. In strict mode, that includes the
+    /// first byte that is not valid UTF-8.
+    fn literal_end(&self, in_word: bool, stop: Option<u8>) -> usize {
+        let rest = &self.text[self.index..];
+        let (lead, close) = (self.lead[0], self.syntax.close()[0]);
+        let found = match (self.syntax.escape.backslash(), in_word) {
+            (false, false) => memchr(lead, rest),
+            (true, false) => memchr2(lead, BACKSLASH, rest),
+            (false, true) => memchr2(lead, close, rest),
+            (true, true) => memchr3(lead, BACKSLASH, close, rest),
+        };
+        let mut length = found.unwrap_or(rest.len());
+        if let Some(stop) = stop {
+            length = memchr2(stop, BACKSLASH, &rest[..length]).unwrap_or(length);
+        }
+        if self.syntax.strict_utf8 {
+            if let Err(error) = str::from_utf8(&rest[..length]) {
+                length = error.valid_up_to();
+            }
+        }
+        self.index + length
+    }
+
+    /// Moves to `end` at once, which has to be in the same node.
+    fn skip(&mut self, end: usize) {
+        let skipped = &self.text[self.index..end];
+        let characters =
+            |bytes: &[u8]| bytes.iter().filter(|byte| !is_continuation(**byte)).count();
+        match memrchr(b'\n', skipped) {
+            Some(last) => {
+                self.position.line += memchr_iter(b'\n', skipped).count();
+                self.position.column = 1 + characters(&skipped[last + 1..]);
+            }
+            None => self.position.column += characters(skipped),
+        }
+        self.position.offset += skipped.len();
+        self.index = end;
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.index).copied()
+    }
+
+    /// Whether `marker` starts at `index`, `None` when the text ends before it
+    /// can be told.
+    fn marker_at(&self, index: usize, marker: &[u8]) -> Option<bool> {
+        let rest = self.text.get(index..).unwrap_or_default();
+        if rest.len() < marker.len() && marker.starts_with(rest) {
+            return None;
+        }
+        Some(rest.starts_with(marker))
+    }
+
+    /// Whether the next bytes close the current variable.
+    fn closes(&self) -> Result<bool, LexError> {
+        match self.marker_at(self.index, self.syntax.close()) {
+            Some(closes) => Ok(closes),
+            None if self.complete => Ok(false),
+            None => Err(LexError::Incomplete),
+        }
+    }
+
+    fn skip_close(&mut self) {
+        for _ in 0..self.syntax.close().len() {
+            self.bump();
+        }
+    }
+
+    /// Index of the first byte from `index` that is not whitespace allowed
+    /// inside the markers.
+    fn whitespace_end(&self, index: usize) -> usize {
+        if !self.syntax.whitespace() {
+            return index;
+        }
+        let rest = self.text.get(index..).unwrap_or_default();
+        index
+            + rest
+                .iter()
+                .take_while(|byte| byte.is_ascii_whitespace())
+                .count()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let end = self.whitespace_end(self.index);
+        self.skip(end);
+    }
+
+    /// Skips the spaces and tabs allowed around the filters of a pipeline.
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(b' ') | Some(b'\t')) {
+            self.bump();
+        }
+    }
+
+    /// The next character if it is valid UTF-8.
+    fn peek_char(&self) -> Option<char> {
+        match decode(&self.text[self.index..]) {
+            Some(Decoded::Char(current_char)) => Some(current_char),
+            _ => None,
+        }
+    }
+
+    fn bump(&mut self) -> u8 {
+        let byte = self.peek().expect("bump is only called after a peek");
+        self.index += 1;
+        self.position.offset += 1;
+        if byte == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else if !is_continuation(byte) {
+            // Every byte that doesn't continue a UTF-8 character counts as a
+            // column, which includes the invalid ones.
+            self.position.column += 1;
+        }
+        byte
+    }
+}
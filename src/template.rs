@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::error::{Error, Position, Result};
+use crate::filter::VariableFilter;
+use crate::pipeline::ValueFilters;
+use crate::render::{MissingVariablePolicy, Renderer, WarningHandler};
+use crate::source::{Env, VariableSource};
+use crate::syntax::{collect_variables, Lexer, Node, Syntax, Variable};
+
+/// A template parsed once, which can then be rendered any number of times.
+///
+/// `ParserBuilder::template` parses one with other options than the defaults.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+    missing: MissingVariablePolicy,
+    filter: VariableFilter,
+    ascii_case: bool,
+    value_filters: ValueFilters,
+    warnings: WarningHandler,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::parse_with(
+            text,
+            &Syntax::default(),
+            MissingVariablePolicy::Empty,
+            VariableFilter::default(),
+            false,
+            ValueFilters::new(),
+            WarningHandler::default(),
+        )
+    }
+
+    pub(crate) fn parse_with(
+        text: &str,
+        syntax: &Syntax,
+        missing: MissingVariablePolicy,
+        filter: VariableFilter,
+        ascii_case: bool,
+        value_filters: ValueFilters,
+        warnings: WarningHandler,
+    ) -> Result<Self> {
+        let mut lexer = Lexer::new(text.as_bytes(), syntax, true, Position::default());
+        let mut nodes = Vec::new();
+        while let Some(node) = lexer.next_node()? {
+            nodes.push(node);
+        }
+        Ok(Self {
+            nodes,
+            missing,
+            filter,
+            ascii_case,
+            value_filters,
+            warnings,
+        })
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Every variable referenced by the template, including the ones in the
+    /// words of operators, in the order they appear.
+    pub fn variables(&self) -> Vec<&Variable> {
+        let mut variables = Vec::new();
+        collect_variables(&self.nodes, &mut variables);
+        variables
+    }
+
+    /// Renders the template with the variables of `source`. Variables that are
+    /// not set are replaced with an empty string, unless the template was
+    /// parsed with another policy.
+    pub fn render<S, W>(&self, source: &S, output: &mut W) -> Result<()>
+    where
+        S: VariableSource + ?Sized,
+        W: Write,
+    {
+        let mut renderer = Renderer {
+            source,
+            missing: self.missing,
+            filter: &self.filter,
+            assigned: &mut HashMap::new(),
+            ascii_case: self.ascii_case,
+            value_filters: &self.value_filters,
+            warnings: &self.warnings,
+        };
+        renderer.render(&self.nodes, output)
+    }
+
+    /// Same as `render`, but into a new `String`.
+    pub fn render_to_string<S>(&self, source: &S) -> Result<String>
+    where
+        S: VariableSource + ?Sized,
+    {
+        let mut output = Vec::new();
+        self.render(source, &mut output)?;
+        Ok(String::from_utf8(output).expect("templates are rendered from strings"))
+    }
+}
+
+/// The filters of pipelines and the warning handler are left out, as
+/// functions can't be compared.
+impl PartialEq for Template {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes == other.nodes
+            && self.missing == other.missing
+            && self.filter == other.filter
+            && self.ascii_case == other.ascii_case
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        Self::parse(text)
+    }
+}
+
+/// Substitutes the variables of the process environment in `template`.
+pub fn substitute(template: &str) -> Result<String> {
+    substitute_with(template, &Env)
+}
+
+/// Substitutes the variables of `source` in `template`.
+pub fn substitute_with<S>(template: &str, source: &S) -> Result<String>
+where
+    S: VariableSource + ?Sized,
+{
+    Template::parse(template)?.render_to_string(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env::set_var;
+
+    use crate::error::Position;
+    use crate::source::VariableSource;
+    use crate::syntax::{Expansion, Node, Operator, Span, Variable};
+    use crate::template::{substitute, substitute_with, Template};
+
+    fn position(line: usize, column: usize, offset: usize) -> Position {
+        Position {
+            line,
+            column,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_substitute() {
+        set_var("TEST_SUBSTITUTE", "from env");
+        assert_eq!(
+            substitute("value: ${TEST_SUBSTITUTE}").unwrap(),
+            "value: from env"
+        );
+    }
+
+    #[test]
+    fn test_substitute_with() {
+        let mut variables = HashMap::new();
+        variables.insert("NAME".to_owned(), "world".to_owned());
+        assert_eq!(
+            substitute_with("hello $NAME${MISSING}", &variables).unwrap(),
+            "hello world"
+        );
+    }
+
+    #[test]
+    fn test_render_to_string() {
+        let template: Template = "${GREETING:-hello} $NAME".parse().unwrap();
+        let english = |name: &str| match name {
+            "NAME" => Some("world".to_owned()),
+            _ => None,
+        };
+        let french = |name: &str| match name {
+            "GREETING" => Some("bonjour".to_owned()),
+            "NAME" => Some("le monde".to_owned()),
+            _ => None,
+        };
+        assert_eq!(template.render_to_string(&english).unwrap(), "hello world");
+        assert_eq!(
+            template.render_to_string(&french).unwrap(),
+            "bonjour le monde"
+        );
+    }
+
+    #[test]
+    fn test_render_to_string_with_trait_object() {
+        let mut variables = HashMap::new();
+        variables.insert("NAME".to_owned(), "world".to_owned());
+        let source: &dyn VariableSource = &variables;
+        let template = Template::parse("hello $NAME").unwrap();
+        assert_eq!(template.render_to_string(source).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(Template::parse("${UNCLOSED").is_err());
+    }
+
+    #[test]
+    fn test_equality() {
+        assert_eq!(
+            Template::parse("$A").unwrap(),
+            Template::parse("$A").unwrap()
+        );
+        assert_ne!(
+            Template::parse("$A").unwrap(),
+            Template::parse("$B").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_nodes() {
+        let template = Template::parse("port: ${PORT:-$DEFAULT}\n$HOST").unwrap();
+        assert_eq!(
+            template.nodes(),
+            &[
+                Node::Text {
+                    text: b"port: ".to_vec(),
+                    span: Span {
+                        start: position(1, 1, 0),
+                        end: position(1, 7, 6),
+                    },
+                },
+                Node::Variable(Variable {
+                    name: "PORT".to_owned(),
+                    braced: true,
+                    expansion: Some(Expansion {
+                        operator: Operator::UseDefault,
+                        null_check: true,
+                        word: vec![Node::Variable(Variable {
+                            name: "DEFAULT".to_owned(),
+                            braced: false,
+                            expansion: None,
+                            filters: Vec::new(),
+                            raw: b"$DEFAULT".to_vec(),
+                            span: Span {
+                                start: position(1, 15, 14),
+                                end: position(1, 23, 22),
+                            },
+                        })],
+                        argument: None,
+                        raw_word: b"$DEFAULT".to_vec(),
+                    }),
+                    filters: Vec::new(),
+                    raw: b"${PORT:-$DEFAULT}".to_vec(),
+                    span: Span {
+                        start: position(1, 7, 6),
+                        end: position(1, 24, 23),
+                    },
+                }),
+                Node::Text {
+                    text: b"\n".to_vec(),
+                    span: Span {
+                        start: position(1, 24, 23),
+                        end: position(2, 1, 24),
+                    },
+                },
+                Node::Variable(Variable {
+                    name: "HOST".to_owned(),
+                    braced: false,
+                    expansion: None,
+                    filters: Vec::new(),
+                    raw: b"$HOST".to_vec(),
+                    span: Span {
+                        start: position(2, 1, 24),
+                        end: position(2, 6, 29),
+                    },
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_many_times() {
+        let template = Template::parse("${NAME:=default} $NAME").unwrap();
+        let mut variables = HashMap::new();
+        assert_eq!(
+            template.render_to_string(&variables).unwrap(),
+            "default default"
+        );
+        variables.insert("NAME".to_owned(), "set".to_owned());
+        assert_eq!(template.render_to_string(&variables).unwrap(), "set set");
+        variables.clear();
+        assert_eq!(
+            template.render_to_string(&variables).unwrap(),
+            "default default"
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        let template =
+            Template::parse("$HOST:${PORT:-${DEFAULT_PORT}}\n${PASSWORD:?is required} $HOST")
+                .unwrap();
+        let variables = template.variables();
+        let names: Vec<&str> = variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["HOST", "PORT", "DEFAULT_PORT", "PASSWORD", "HOST"]
+        );
+
+        let port = variables[1].expansion.as_ref().unwrap();
+        assert_eq!(port.to_string(), ":-");
+        assert_eq!(port.raw_word, b"${DEFAULT_PORT}");
+        let password = variables[3].expansion.as_ref().unwrap();
+        assert_eq!(password.operator, Operator::Required);
+        assert_eq!(password.raw_word, b"is required");
+        assert_eq!(variables[3].span.start, position(2, 1, 31));
+    }
+
+    #[test]
+    fn test_string_operators() {
+        let template = Template::parse("${#NAME} ${PATH//$FROM/$TO} ${FILE:1}").unwrap();
+        let variables = template.variables();
+        let names: Vec<&str> = variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["NAME", "PATH", "FROM", "TO", "FILE"]);
+
+        let operators: Vec<Operator> = template
+            .nodes()
+            .iter()
+            .filter_map(|node| match node {
+                Node::Variable(variable) => variable.expansion.as_ref(),
+                Node::Text { .. } => None,
+            })
+            .map(|expansion| expansion.operator)
+            .collect();
+        assert_eq!(
+            operators,
+            vec![Operator::Length, Operator::ReplaceAll, Operator::Substring]
+        );
+
+        let replace = variables[1].expansion.as_ref().unwrap();
+        assert_eq!(replace.to_string(), "//");
+        assert_eq!(replace.raw_word, b"$FROM/$TO");
+        assert_eq!(replace.argument.as_ref().map(Vec::len), Some(1));
+    }
+}